@@ -1,7 +1,215 @@
+use axum::http::Method;
 use base64::Engine;
 use base64::prelude::BASE64_STANDARD;
 use bcrypt::HashParts;
+use std::collections::HashMap;
 use std::str::FromStr;
+use std::sync::{LazyLock, RwLock};
+
+use crate::CONFIG;
+use crate::secrets::{SECRETS, Secret};
+
+/// Users allowed to send requests. Configured by users file or security token.
+pub static USERS: LazyLock<UserStore> =
+    LazyLock::new(|| UserStore::new(load_users().unwrap_or_default()));
+
+/// Name of the user who authenticated the request
+#[derive(Clone, Debug)]
+pub struct AuthenticatedUser(pub String);
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum AuthError {
+    Unauthorized,
+    /// The named user is not allowed to use the request method
+    Forbidden(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Permission {
+    All,
+    PostOnly,
+    DeleteOnly,
+}
+
+impl Permission {
+    pub fn allows(self, method: &Method) -> bool {
+        match self {
+            Permission::All => true,
+            Permission::PostOnly => method != Method::DELETE,
+            Permission::DeleteOnly => method != Method::POST,
+        }
+    }
+}
+
+impl FromStr for Permission {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "all" => Ok(Permission::All),
+            "post" => Ok(Permission::PostOnly),
+            "delete" => Ok(Permission::DeleteOnly),
+            _ => Err(format!("unknown permission '{value}'")),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct User {
+    hash: String,
+    permission: Permission,
+}
+
+/// Users with bcrypt hashed passwords, e.g. read from a htpasswd file
+#[derive(Debug, Default)]
+pub struct Users(HashMap<String, User>);
+
+impl Users {
+    /// Parses lines in htpasswd format with an optional permission `all`, `post` or `delete`
+    /// appended: `username:bcrypt-hash[:permission]`
+    pub fn parse(content: &str) -> Result<Self, String> {
+        let mut users = HashMap::new();
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut parts = line.split(':');
+            let (Some(username), Some(hash)) = (parts.next(), parts.next()) else {
+                return Err(format!("line {}: expected 'username:hash'", number + 1));
+            };
+            if HashParts::from_str(hash).is_err() {
+                return Err(format!(
+                    "line {}: no valid bcrypt hash for user '{username}'",
+                    number + 1
+                ));
+            }
+            let permission = match parts.next() {
+                Some(permission) => permission
+                    .parse()
+                    .map_err(|err| format!("line {}: {err}", number + 1))?,
+                None => Permission::All,
+            };
+
+            users.insert(
+                username.to_string(),
+                User {
+                    hash: hash.to_string(),
+                    permission,
+                },
+            );
+        }
+        Ok(Self(users))
+    }
+
+    pub fn read(path: &str) -> Result<Self, String> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|err| format!("Cannot read users file '{path}': {err}"))?;
+        Self::parse(&content).map_err(|err| format!("Invalid users file '{path}': {err}"))
+    }
+
+    /// Single user with all permissions given by security token
+    pub fn from_token(token: &str) -> Result<Self, String> {
+        if !is_valid_brypt_hash(token) {
+            return Err("given token is not a valid BCrypt token".to_string());
+        }
+        let (username, hash) = split_username_password(token);
+        Ok(Self(HashMap::from([(
+            username,
+            User {
+                hash,
+                permission: Permission::All,
+            },
+        )])))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Returns the name and permission of the user authenticated by HTTP basic auth header
+    pub fn authenticate(&self, auth_header: &str) -> Option<(&str, Permission)> {
+        let (username, password) = basic_auth_credentials(auth_header)?;
+        let (username, user) = self.0.get_key_value(&username)?;
+        match bcrypt::verify(password, &user.hash) {
+            Ok(true) => Some((username, user.permission)),
+            _ => None,
+        }
+    }
+}
+
+/// Users replaceable at runtime
+pub struct UserStore(RwLock<Users>);
+
+impl UserStore {
+    pub fn new(users: Users) -> Self {
+        Self(RwLock::new(users))
+    }
+
+    pub fn replace(&self, users: Users) {
+        if let Ok(mut current) = self.0.write() {
+            *current = users;
+        }
+    }
+
+    /// Reloads users as configured and returns the number of users
+    pub fn reload(&self) -> Result<usize, String> {
+        let users = load_users()?;
+        let len = users.len();
+        self.replace(users);
+        Ok(len)
+    }
+
+    /// Returns the name of the user authenticated by HTTP basic auth header, if the user is
+    /// allowed to use the request method
+    pub fn authenticate(&self, auth_header: &str, method: &Method) -> Result<String, AuthError> {
+        let users = self.0.read().map_err(|_| AuthError::Unauthorized)?;
+        match users.authenticate(auth_header) {
+            Some((username, permission)) if permission.allows(method) => Ok(username.to_string()),
+            Some((username, _)) => Err(AuthError::Forbidden(username.to_string())),
+            None => Err(AuthError::Unauthorized),
+        }
+    }
+}
+
+fn load_users() -> Result<Users, String> {
+    match (&CONFIG.users_file, SECRETS.get(Secret::Token)) {
+        (Some(users_file), _) => Users::read(users_file),
+        (None, Some(token)) => Users::from_token(&token),
+        // Only bearer authentication is used
+        (None, None) => Ok(Users::default()),
+    }
+}
+
+/// Reloads secrets and users on SIGHUP
+#[cfg(unix)]
+pub async fn reload_on_hangup() {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let Ok(mut hangup) = signal(SignalKind::hangup()) else {
+        log::error!("Cannot listen for SIGHUP to reload users");
+        return;
+    };
+    while hangup.recv().await.is_some() {
+        match SECRETS.reload() {
+            Ok(changed) => {
+                for secret in changed {
+                    if secret.is_reloadable() {
+                        log::info!("Reloaded {secret}");
+                    } else {
+                        log::warn!("Changed {secret} will be used after restart");
+                    }
+                }
+            }
+            Err(err) => log::error!("Cannot reload secrets, keeping previous ones: {err}"),
+        }
+        match USERS.reload() {
+            Ok(len) => log::info!("Reloaded {len} user(s)"),
+            Err(err) => log::error!("Cannot reload users, keeping previous ones: {err}"),
+        }
+    }
+}
 
 pub fn split_username_password(auth: &str) -> (String, String) {
     let split = auth.split(':').collect::<Vec<_>>();
@@ -16,38 +224,30 @@ pub fn is_valid_brypt_hash(auth: &str) -> bool {
     HashParts::from_str(&hash).is_ok()
 }
 
-#[allow(clippy::module_name_repetitions)]
-pub fn check_basic_auth(auth_header: &str, expected_token: &str) -> bool {
+/// Returns username and password of HTTP basic auth header
+fn basic_auth_credentials(auth_header: &str) -> Option<(String, String)> {
     let split = auth_header.split(' ').collect::<Vec<_>>();
     if split.len() == 2
         && split.first().map(|first| first.to_lowercase()) == Some("basic".into())
         && let Ok(auth) = BASE64_STANDARD.decode(split.last().unwrap_or(&""))
         && let Ok(auth) = String::from_utf8(auth)
     {
-        let split_token = expected_token.split(':').collect::<Vec<_>>();
-        let expected_username = if split_token.len() == 2 {
-            split_token.first().unwrap_or(&"token")
-        } else {
-            &"token"
-        };
-        let expected_token = split_token.get(1).unwrap_or(&expected_token);
-
-        let (username, hash) = &split_username_password(&auth);
-        if username == expected_username
-            && let Ok(true) = bcrypt::verify(hash, expected_token)
-        {
-            return true;
-        }
+        return Some(split_username_password(&auth));
     }
-
-    false
+    None
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::auth::{check_basic_auth, is_valid_brypt_hash, split_username_password};
+    use crate::auth::{Permission, Users, is_valid_brypt_hash, split_username_password};
+    use axum::http::Method;
     use rstest::rstest;
 
+    fn check_basic_auth(auth_header: &str, expected_token: &str) -> bool {
+        Users::from_token(expected_token)
+            .is_ok_and(|users| users.authenticate(auth_header).is_some())
+    }
+
     // plain text value 'very-secret'
     const EXPECTED_TOKEN: &str = "$2y$05$LIIFF4Rbi3iRVA4UIqxzPeTJ0NOn/cV2hDnSKFftAMzbEZRa42xSG";
 
@@ -143,4 +343,61 @@ mod tests {
     fn should_split_username_tokenhash(#[case] input: &str, #[case] expected: (String, String)) {
         assert_eq!(split_username_password(input), expected);
     }
+
+    const USERS_FILE: &str = "# Sending systems
+tds:$2y$05$LIIFF4Rbi3iRVA4UIqxzPeTJ0NOn/cV2hDnSKFftAMzbEZRa42xSG
+lab:$2y$05$LIIFF4Rbi3iRVA4UIqxzPeTJ0NOn/cV2hDnSKFftAMzbEZRa42xSG:post
+
+upload:$2y$05$LIIFF4Rbi3iRVA4UIqxzPeTJ0NOn/cV2hDnSKFftAMzbEZRa42xSG:delete
+";
+
+    #[rstest]
+    // tds:very-secret
+    #[case("Basic dGRzOnZlcnktc2VjcmV0", Some(("tds", Permission::All)))]
+    // lab:very-secret
+    #[case("Basic bGFiOnZlcnktc2VjcmV0", Some(("lab", Permission::PostOnly)))]
+    // upload:very-secret
+    #[case(
+        "Basic dXBsb2FkOnZlcnktc2VjcmV0",
+        Some(("upload", Permission::DeleteOnly))
+    )]
+    // lab:123456789
+    #[case("Basic bGFiOjEyMzQ1Njc4OQ==", None)]
+    // token:very-secret
+    #[case("Basic dG9rZW46dmVyeS1zZWNyZXQ=", None)]
+    fn should_authenticate_users(
+        #[case] auth_header: &str,
+        #[case] expected: Option<(&str, Permission)>,
+    ) {
+        let users = Users::parse(USERS_FILE).unwrap_or_default();
+        assert_eq!(users.len(), 3);
+        assert_eq!(users.authenticate(auth_header), expected);
+    }
+
+    #[rstest]
+    #[case("tds", "missing hash")]
+    #[case("tds:very-secret", "no bcrypt hash")]
+    #[case(
+        "tds:$2y$05$LIIFF4Rbi3iRVA4UIqxzPeTJ0NOn/cV2hDnSKFftAMzbEZRa42xSG:get",
+        "unknown permission"
+    )]
+    fn should_reject_invalid_users_file(#[case] content: &str, #[case] reason: &str) {
+        assert!(Users::parse(content).is_err(), "{reason}");
+    }
+
+    #[rstest]
+    #[case(Permission::All, Method::POST, true)]
+    #[case(Permission::All, Method::DELETE, true)]
+    #[case(Permission::PostOnly, Method::POST, true)]
+    #[case(Permission::PostOnly, Method::DELETE, false)]
+    #[case(Permission::DeleteOnly, Method::POST, false)]
+    #[case(Permission::DeleteOnly, Method::DELETE, true)]
+    #[case(Permission::DeleteOnly, Method::GET, true)]
+    fn should_check_permission(
+        #[case] permission: Permission,
+        #[case] method: Method,
+        #[case] expected: bool,
+    ) {
+        assert_eq!(permission.allows(&method), expected);
+    }
 }
@@ -0,0 +1,275 @@
+use serde::Serialize;
+use std::mem;
+
+use crate::problem::{Problem, ProblemType};
+use crate::sender::SendReport;
+use crate::validation::Issue;
+
+/// Format of a request body containing multiple MTB files
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BulkFormat {
+    /// One MTB file per line
+    NdJson,
+    /// JSON array of MTB files
+    JsonArray,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ArrayState {
+    Start,
+    Items,
+    End,
+}
+
+/// Splits a streamed request body into single MTB files. Only the MTB file currently read
+/// is buffered.
+pub struct ItemSplitter {
+    format: BulkFormat,
+    max_item_size: usize,
+    item: Vec<u8>,
+    state: ArrayState,
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+}
+
+impl ItemSplitter {
+    pub fn new(format: BulkFormat, max_item_size: usize) -> Self {
+        Self {
+            format,
+            max_item_size,
+            item: vec![],
+            state: ArrayState::Start,
+            depth: 0,
+            in_string: false,
+            escaped: false,
+        }
+    }
+
+    /// Returns all MTB files completed by the chunk of the request body
+    pub fn push(&mut self, chunk: &[u8]) -> Result<Vec<Vec<u8>>, Problem> {
+        let mut items = vec![];
+        for &byte in chunk {
+            match self.format {
+                BulkFormat::NdJson => self.push_line_byte(byte, &mut items),
+                BulkFormat::JsonArray => self
+                    .push_array_byte(byte, &mut items)
+                    .map_err(|err| Problem::new(ProblemType::InvalidJson).with_detail(err))?,
+            }
+            if self.item.len() > self.max_item_size {
+                return Err(Problem::new(ProblemType::PayloadTooLarge)
+                    .with_detail(&format!(
+                        "The MTB file exceeds the limit of {} bytes",
+                        self.max_item_size
+                    ))
+                    .with_limit(self.max_item_size));
+            }
+        }
+        Ok(items)
+    }
+
+    /// Returns the last MTB file after the request body has been read completely
+    pub fn finish(&mut self) -> Result<Option<Vec<u8>>, Problem> {
+        let err = match (self.format, self.state) {
+            (BulkFormat::NdJson, _) => return Ok(self.take_item()),
+            (BulkFormat::JsonArray, ArrayState::End) => return Ok(None),
+            (BulkFormat::JsonArray, ArrayState::Start) => "Expected a JSON array of MTB files",
+            (BulkFormat::JsonArray, ArrayState::Items) => "Incomplete JSON array of MTB files",
+        };
+        Err(Problem::new(ProblemType::InvalidJson).with_detail(err))
+    }
+
+    /// Takes the current MTB file unless it is blank
+    fn take_item(&mut self) -> Option<Vec<u8>> {
+        let item = mem::take(&mut self.item);
+        if item.iter().all(u8::is_ascii_whitespace) {
+            return None;
+        }
+        Some(item)
+    }
+
+    fn push_line_byte(&mut self, byte: u8, items: &mut Vec<Vec<u8>>) {
+        if byte == b'\n' {
+            items.extend(self.take_item());
+        } else {
+            self.item.push(byte);
+        }
+    }
+
+    fn push_array_byte(&mut self, byte: u8, items: &mut Vec<Vec<u8>>) -> Result<(), &'static str> {
+        match self.state {
+            ArrayState::Start if byte == b'[' => self.state = ArrayState::Items,
+            ArrayState::Start | ArrayState::End if byte.is_ascii_whitespace() => {}
+            ArrayState::Start => return Err("Expected a JSON array of MTB files"),
+            ArrayState::End => return Err("Unexpected content after JSON array"),
+            ArrayState::Items if self.in_string => {
+                self.item.push(byte);
+                if self.escaped {
+                    self.escaped = false;
+                } else if byte == b'\\' {
+                    self.escaped = true;
+                } else if byte == b'"' {
+                    self.in_string = false;
+                }
+            }
+            ArrayState::Items => match byte {
+                b',' | b']' if self.depth == 0 => {
+                    items.extend(self.take_item());
+                    if byte == b']' {
+                        self.state = ArrayState::End;
+                    }
+                }
+                _ if self.item.is_empty() && byte.is_ascii_whitespace() => {}
+                b'"' => {
+                    self.in_string = true;
+                    self.item.push(byte);
+                }
+                b'{' | b'[' => {
+                    self.depth += 1;
+                    self.item.push(byte);
+                }
+                b'}' | b']' => {
+                    self.depth = self.depth.saturating_sub(1);
+                    self.item.push(byte);
+                }
+                _ => self.item.push(byte),
+            },
+        }
+        Ok(())
+    }
+}
+
+/// Result of a single MTB file of a bulk request
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BulkItemResult {
+    pub index: usize,
+    pub status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub partition: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offset: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<i64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<Issue>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub problem: Option<Problem>,
+}
+
+impl BulkItemResult {
+    pub fn accepted(
+        index: usize,
+        report: SendReport,
+        topic: Option<String>,
+        warnings: Vec<Issue>,
+    ) -> Self {
+        let (topic, partition, offset, timestamp) = match report.delivery {
+            Some(delivery) => (
+                Some(delivery.topic),
+                Some(delivery.partition),
+                Some(delivery.offset),
+                delivery.timestamp,
+            ),
+            None => (topic, None, None, None),
+        };
+        Self {
+            index,
+            status: if report.unchanged { 200 } else { 202 },
+            request_id: Some(report.request_id),
+            topic,
+            partition,
+            offset,
+            timestamp,
+            warnings,
+            problem: None,
+        }
+    }
+
+    pub fn rejected(index: usize, problem: Problem) -> Self {
+        Self {
+            index,
+            status: problem.status,
+            request_id: None,
+            topic: None,
+            partition: None,
+            offset: None,
+            timestamp: None,
+            warnings: vec![],
+            problem: Some(problem),
+        }
+    }
+
+    pub fn is_accepted(&self) -> bool {
+        self.problem.is_none()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bulk::{BulkFormat, ItemSplitter};
+
+    #[allow(clippy::expect_used)]
+    fn split(format: BulkFormat, chunks: &[&str]) -> Result<Vec<String>, u16> {
+        let mut splitter = ItemSplitter::new(format, 64);
+        let mut items = vec![];
+        for chunk in chunks {
+            items.extend(
+                splitter
+                    .push(chunk.as_bytes())
+                    .map_err(|problem| problem.status)?,
+            );
+        }
+        items.extend(splitter.finish().map_err(|problem| problem.status)?);
+        Ok(items
+            .into_iter()
+            .map(|item| String::from_utf8(item).expect("valid UTF-8"))
+            .collect())
+    }
+
+    #[test]
+    fn should_split_lines() {
+        assert_eq!(
+            split(
+                BulkFormat::NdJson,
+                &["{\"id\":1}\n{\"id\"", ":2}\n\n  \n", "{\"id\":3}"]
+            ),
+            Ok(vec![
+                "{\"id\":1}".to_string(),
+                "{\"id\":2}".to_string(),
+                "{\"id\":3}".to_string()
+            ])
+        );
+    }
+
+    #[test]
+    fn should_split_array_items() {
+        assert_eq!(
+            split(
+                BulkFormat::JsonArray,
+                &[
+                    " [ {\"id\":1,\"a\":[1,2]}",
+                    ", {\"id\":\"x],\\\"}\"}",
+                    "]\n"
+                ]
+            ),
+            Ok(vec![
+                "{\"id\":1,\"a\":[1,2]}".to_string(),
+                "{\"id\":\"x],\\\"}\"}".to_string()
+            ])
+        );
+        assert_eq!(split(BulkFormat::JsonArray, &["[]"]), Ok(vec![]));
+    }
+
+    #[test]
+    fn should_reject_invalid_bodies() {
+        assert_eq!(split(BulkFormat::JsonArray, &["{\"id\":1}"]), Err(400));
+        assert_eq!(split(BulkFormat::JsonArray, &["[{\"id\":1}"]), Err(400));
+        assert_eq!(split(BulkFormat::JsonArray, &["[{\"id\":1}] {}"]), Err(400));
+        assert_eq!(split(BulkFormat::NdJson, &[&"x".repeat(65)]), Err(413));
+    }
+}
@@ -1,9 +1,31 @@
-use clap::Parser;
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::{ArgAction, ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand};
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fmt::Write;
+
+use crate::config::{ConfigFile, SECRETS, SECTIONS, is_secret_property};
+use crate::kafka;
+use crate::kafka::{KafkaProperty, SaslMechanism};
+use crate::metadata::SourceHeader;
+use crate::outbox::FsyncPolicy;
+use crate::pseudonym::PseudonymizerType;
+use crate::tls::ClientCertAuth;
+use crate::validation::RuleSeverity;
 
 #[derive(Parser)]
 #[command(author, version, about)]
 #[command(arg_required_else_help(true))]
 pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    #[arg(
+        long,
+        env = "CONFIG_FILE",
+        help = "Configuration file in TOML format. Environment variables and command line options take precedence"
+    )]
+    pub config: Option<String>,
     #[arg(
         long,
         env = "LISTEN",
@@ -11,13 +33,138 @@ pub struct Cli {
         help = "Address and port for HTTP requests"
     )]
     pub listen: String,
+    #[arg(
+        long,
+        env = "METRICS_LISTEN",
+        help = "Separate address and port for the metrics endpoint. If not set, metrics are available using the address for HTTP requests"
+    )]
+    pub metrics_listen: Option<String>,
+    #[arg(
+        long,
+        env = "OTEL_EXPORTER_OTLP_ENDPOINT",
+        help = "Base URL of an OTLP collector using HTTP, e.g. 'http://localhost:4318'. If set, spans of sent MTB files are exported"
+    )]
+    pub otlp_endpoint: Option<String>,
+    #[arg(
+        long,
+        env = "OTEL_SERVICE_NAME",
+        default_value = "mv64e-rest-to-kafka-gateway",
+        help = "Service name of exported spans"
+    )]
+    pub otlp_service_name: String,
+    #[arg(
+        long,
+        env = "TLS_CERT_FILE",
+        requires = "tls_key_file",
+        help = "Certificate file for HTTPS. Reloaded on change"
+    )]
+    pub tls_cert_file: Option<String>,
+    #[arg(
+        long,
+        env = "TLS_KEY_FILE",
+        requires = "tls_cert_file",
+        help = "Key file for HTTPS. Reloaded on change"
+    )]
+    pub tls_key_file: Option<String>,
+    #[arg(
+        long,
+        env = "TLS_CLIENT_CA_FILE",
+        requires = "tls_cert_file",
+        help = "CA file to verify client certificates. If set, client certificates are required except for health checks and metrics"
+    )]
+    pub tls_client_ca_file: Option<String>,
+    #[arg(
+        long,
+        env = "TLS_CLIENT_ALLOW",
+        value_delimiter = ',',
+        help = "Allowed subject DNs or alternative names of client certificates. Any verified client certificate is allowed if empty"
+    )]
+    pub tls_client_allow: Vec<String>,
+    #[arg(
+        long,
+        env = "TLS_CLIENT_AUTH",
+        value_enum,
+        default_value = "complement",
+        help = "Whether an allowed client certificate complements or replaces HTTP authentication"
+    )]
+    pub tls_client_auth: ClientCertAuth,
+    #[arg(
+        long,
+        env = "MAX_BODY_SIZE",
+        help = "Maximum size of request bodies containing a single MTB file in bytes. Defaults to the Kafka property 'message.max.bytes'"
+    )]
+    pub max_body_size: Option<usize>,
+    #[arg(
+        long,
+        env = "MAX_BULK_BODY_SIZE",
+        help = "Maximum size of request bodies containing multiple MTB files in bytes. Not limited if not set"
+    )]
+    pub max_bulk_body_size: Option<usize>,
+    #[arg(
+        long,
+        env = "MAX_DECOMPRESSED_SIZE",
+        help = "Maximum size of an MTB file in bytes after decompressing a request body using gzip, deflate, br or zstd. Defaults to the Kafka property 'message.max.bytes'"
+    )]
+    pub max_decompressed_size: Option<usize>,
     #[arg(
         long,
         alias = "security-token",
         env = "SECURITY_TOKEN",
+        required_unless_present_any = ["token_file", "users_file", "oidc_jwks_file", "oidc_jwks_url"],
         help = "bcrypt hashed Security Token"
     )]
-    pub token: String,
+    pub token: Option<String>,
+    #[arg(
+        long,
+        env = "SECURITY_TOKEN_FILE",
+        help = "File containing the bcrypt hashed Security Token. Reloaded on SIGHUP"
+    )]
+    pub token_file: Option<String>,
+    #[arg(
+        long,
+        env = "USERS_FILE",
+        help = "htpasswd file with bcrypt hashed passwords of users, used instead of the Security Token. Reloaded on SIGHUP"
+    )]
+    pub users_file: Option<String>,
+    #[arg(
+        long,
+        env = "OIDC_JWKS_FILE",
+        help = "JWKS file with keys to validate bearer tokens"
+    )]
+    pub oidc_jwks_file: Option<String>,
+    #[arg(
+        long,
+        env = "OIDC_JWKS_URL",
+        help = "JWKS URL with keys to validate bearer tokens, e.g. of a Keycloak realm"
+    )]
+    pub oidc_jwks_url: Option<String>,
+    #[arg(
+        long,
+        env = "OIDC_JWKS_CACHE_TTL",
+        default_value = "300",
+        help = "Seconds to cache keys requested from JWKS URL"
+    )]
+    pub oidc_jwks_cache_ttl: u64,
+    #[arg(long, env = "OIDC_ISSUER", help = "Required issuer of bearer tokens")]
+    pub oidc_issuer: Option<String>,
+    #[arg(
+        long,
+        env = "OIDC_AUDIENCE",
+        help = "Required audience of bearer tokens"
+    )]
+    pub oidc_audience: Option<String>,
+    #[arg(
+        long,
+        env = "OIDC_POST_SCOPE",
+        help = "Scope or role of bearer tokens required to send MTB files"
+    )]
+    pub oidc_post_scope: Option<String>,
+    #[arg(
+        long,
+        env = "OIDC_DELETE_SCOPE",
+        help = "Scope or role of bearer tokens required to delete patients"
+    )]
+    pub oidc_delete_scope: Option<String>,
     #[arg(
         long,
         alias = "kafka-servers",
@@ -54,4 +201,519 @@ pub struct Cli {
     pub ssl_key_file: Option<String>,
     #[arg(long, env = "KAFKA_SSL_KEY_PASSWORD", help = "The SSL key password")]
     pub ssl_key_password: Option<String>,
+    #[arg(
+        long,
+        env = "KAFKA_SSL_KEY_PASSWORD_FILE",
+        help = "File containing the SSL key password"
+    )]
+    pub ssl_key_password_file: Option<String>,
+    #[arg(
+        long,
+        env = "KAFKA_SASL_MECHANISM",
+        value_enum,
+        help = "SASL mechanism used to authenticate to Kafka"
+    )]
+    pub sasl_mechanism: Option<SaslMechanism>,
+    #[arg(
+        long,
+        env = "KAFKA_SASL_PLAINTEXT",
+        help = "Use SASL without SSL connection to Kafka, e.g. for development"
+    )]
+    pub sasl_plaintext: bool,
+    #[arg(long, env = "KAFKA_SASL_USERNAME", help = "SASL username")]
+    pub sasl_username: Option<String>,
+    #[arg(long, env = "KAFKA_SASL_PASSWORD", help = "SASL password")]
+    pub sasl_password: Option<String>,
+    #[arg(
+        long,
+        env = "KAFKA_SASL_PASSWORD_FILE",
+        help = "File containing the SASL password"
+    )]
+    pub sasl_password_file: Option<String>,
+    #[arg(
+        long,
+        env = "KAFKA_SASL_OAUTH_TOKEN_ENDPOINT",
+        help = "Token endpoint to request OAUTHBEARER tokens using client credentials"
+    )]
+    pub sasl_oauth_token_endpoint: Option<String>,
+    #[arg(
+        long,
+        env = "KAFKA_SASL_OAUTH_CLIENT_ID",
+        help = "Client ID to request OAUTHBEARER tokens"
+    )]
+    pub sasl_oauth_client_id: Option<String>,
+    #[arg(
+        long,
+        env = "KAFKA_SASL_OAUTH_CLIENT_SECRET",
+        help = "Client secret to request OAUTHBEARER tokens"
+    )]
+    pub sasl_oauth_client_secret: Option<String>,
+    #[arg(
+        long,
+        env = "KAFKA_SASL_OAUTH_CLIENT_SECRET_FILE",
+        help = "File containing the client secret to request OAUTHBEARER tokens. Reloaded on SIGHUP"
+    )]
+    pub sasl_oauth_client_secret_file: Option<String>,
+    #[arg(
+        long,
+        env = "KAFKA_SASL_OAUTH_SCOPE",
+        help = "Scope of requested OAUTHBEARER tokens"
+    )]
+    pub sasl_oauth_scope: Option<String>,
+    #[arg(
+        long = "kafka-property",
+        help = "Additional property of the Kafka producer as '<key>=<value>', e.g. 'acks=all'. Can also be set using KAFKA_PROP_* environment variables, e.g. KAFKA_PROP_LINGER_MS=10"
+    )]
+    pub kafka_properties: Vec<KafkaProperty>,
+    #[arg(
+        long,
+        env = "OUTBOX_DIR",
+        help = "Directory for a persistent outbox. If set, requests are stored and accepted before sending them to Kafka"
+    )]
+    pub outbox_dir: Option<String>,
+    #[arg(
+        long,
+        env = "OUTBOX_MAX_SIZE",
+        default_value = "1073741824",
+        help = "Maximum size of all records pending in the outbox in bytes"
+    )]
+    pub outbox_max_size: u64,
+    #[arg(
+        long,
+        env = "OUTBOX_FSYNC",
+        value_enum,
+        default_value = "always",
+        help = "When to sync outbox records to disk"
+    )]
+    pub outbox_fsync: FsyncPolicy,
+    #[arg(
+        long,
+        env = "KAFKA_RESPONSE_TOPIC",
+        help = "Kafka Topic with responses of the ETL processor. If set, the request status can be requested"
+    )]
+    pub response_topic: Option<String>,
+    #[arg(
+        long,
+        env = "KAFKA_GROUP_ID",
+        default_value = "mv64e-rest-to-kafka-gateway",
+        help = "Kafka Group ID used to consume responses"
+    )]
+    pub response_group_id: String,
+    #[arg(
+        long,
+        env = "KAFKA_TRANSACTIONAL_ID",
+        help = "Transactional ID of the Kafka producer. If set, MTB files are sent in transactions and all MTB files of a bulk request are committed atomically. Each single MTB file is sent in a transaction of its own and only one transaction is sent at a time, limiting the throughput. Must be unique for each instance"
+    )]
+    pub transactional_id: Option<String>,
+    #[arg(
+        long,
+        env = "MAX_TRANSACTION_SIZE",
+        default_value = "1000",
+        help = "Maximum number of MTB files of a bulk request sent in a single transaction. Larger bulk requests are rejected"
+    )]
+    pub max_transaction_size: usize,
+    #[arg(
+        long,
+        env = "REQUEST_STATUS_FILE",
+        help = "File to persist the request status"
+    )]
+    pub request_status_file: Option<String>,
+    #[arg(
+        long,
+        env = "WAIT_FOR_DELIVERY",
+        help = "Always wait for delivery to Kafka and respond with the resulting Kafka record"
+    )]
+    pub wait_for_delivery: bool,
+    #[arg(
+        long,
+        env = "IDEMPOTENCY_TTL",
+        default_value = "86400",
+        help = "Seconds to remember idempotency keys of accepted MTB files. Repeated requests using the same key respond with the original request ID. Disabled if 0"
+    )]
+    pub idempotency_ttl: u64,
+    #[arg(
+        long,
+        env = "IDEMPOTENCY_CONTENT_HASH",
+        help = "Use the content hash of MTB files as idempotency key if no Idempotency-Key header is given"
+    )]
+    pub idempotency_content_hash: bool,
+    #[arg(
+        long,
+        env = "REQUEST_ID_TTL",
+        default_value = "86400",
+        help = "Seconds to reject reusing a request ID given by X-Request-Id header. Not checked if 0"
+    )]
+    pub request_id_ttl: u64,
+    #[arg(
+        long = "source-header",
+        env = "SOURCE_HEADERS",
+        value_delimiter = ',',
+        help = "HTTP header with source metadata copied into Kafka headers, e.g. 'X-Dnpm-Source-System'. Only headers starting with 'X-Dnpm-' are allowed"
+    )]
+    pub source_headers: Vec<SourceHeader>,
+    #[arg(
+        long,
+        env = "UNCHANGED_STORE_FILE",
+        help = "File to store content hashes of sent MTB files by patient ID. If set, MTB files unchanged since the last request of the patient are not sent again"
+    )]
+    pub unchanged_store_file: Option<String>,
+    #[arg(
+        long = "validation-rule",
+        env = "VALIDATION_RULES",
+        value_delimiter = ',',
+        help = "Severity of a validation rule as '<rule>=<error|warning|ignore>'. Rules: patient-reference, diagnosis-reference, specimen-reference, variant-reference"
+    )]
+    pub validation_rules: Vec<RuleSeverity>,
+    #[arg(
+        long,
+        env = "PSEUDONYMIZER",
+        value_enum,
+        help = "Pseudonymize patient IDs before sending MTB files to Kafka"
+    )]
+    pub pseudonymizer: Option<PseudonymizerType>,
+    #[arg(
+        long,
+        env = "PSEUDONYM_HMAC_KEY",
+        help = "Secret key used to create HMAC pseudonyms"
+    )]
+    pub pseudonym_hmac_key: Option<String>,
+    #[arg(
+        long,
+        env = "PSEUDONYM_HMAC_KEY_FILE",
+        help = "File containing the secret key used to create HMAC pseudonyms"
+    )]
+    pub pseudonym_hmac_key_file: Option<String>,
+    #[arg(
+        long,
+        env = "PSEUDONYM_MAPPING_FILE",
+        help = "File to store the mapping of patient IDs to random pseudonyms"
+    )]
+    pub pseudonym_mapping_file: Option<String>,
+    #[arg(
+        long,
+        env = "GPAS_URL",
+        help = "URL of the gPAS FHIR operation $pseudonymizeAllowCreate"
+    )]
+    pub gpas_url: Option<String>,
+    #[arg(long, env = "GPAS_DOMAIN", help = "gPAS domain of patient pseudonyms")]
+    pub gpas_domain: Option<String>,
+    #[arg(
+        long,
+        env = "GPAS_USERNAME",
+        help = "Username for gPAS basic authentication"
+    )]
+    pub gpas_username: Option<String>,
+    #[arg(
+        long,
+        env = "GPAS_PASSWORD",
+        help = "Password for gPAS basic authentication"
+    )]
+    pub gpas_password: Option<String>,
+    #[arg(
+        long,
+        env = "GPAS_PASSWORD_FILE",
+        help = "File containing the password for gPAS basic authentication. Reloaded on SIGHUP"
+    )]
+    pub gpas_password_file: Option<String>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    #[command(about = "Inspect the configuration")]
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ConfigCommand {
+    #[command(about = "Print the effective configuration with secrets masked")]
+    Check,
+}
+
+const MASK: &str = "********";
+
+/// Parses the command line using options of the configuration file
+#[cfg(not(test))]
+pub fn parse() -> Cli {
+    parse_from(std::env::args_os()).unwrap_or_else(|err| err.exit())
+}
+
+pub fn parse_from(args: impl IntoIterator<Item = impl Into<OsString>>) -> Result<Cli, clap::Error> {
+    let (matches, _) = layered_matches(args)?;
+    Cli::from_arg_matches(&matches)
+}
+
+/// Options of the configuration file are used like command line options unless set on the
+/// command line or using environment variables. Returns all matches and the IDs of options
+/// taken from the configuration file.
+fn layered_matches(
+    args: impl IntoIterator<Item = impl Into<OsString>>,
+) -> Result<(ArgMatches, Vec<String>), clap::Error> {
+    let args = args.into_iter().map(Into::into).collect::<Vec<OsString>>();
+    let mut command = Cli::command();
+    // Missing required options might be part of the configuration file
+    let matches = command
+        .clone()
+        .ignore_errors(true)
+        .try_get_matches_from(&args)?;
+    let Some(path) = matches.get_one::<String>("config") else {
+        return Ok((command.try_get_matches_from(args)?, vec![]));
+    };
+    let options = ConfigFile::read(path)
+        .and_then(|config_file| config_file.options())
+        .map_err(|err| command.error(ErrorKind::Io, err))?;
+
+    let mut layered = args.first().cloned().into_iter().collect::<Vec<_>>();
+    let mut from_file = vec![];
+    for option in options {
+        let Some(arg) = command
+            .get_arguments()
+            .find(|arg| arg.get_id() == option.id.as_str())
+        else {
+            continue;
+        };
+        if matches!(
+            matches.value_source(&option.id),
+            Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+        ) {
+            continue;
+        }
+        let flag = format!("--{}", arg.get_long().unwrap_or_default());
+        if arg.get_action().takes_values() {
+            // Values starting with a hyphen must not be parsed as options
+            for value in option.values {
+                layered.push(format!("{flag}={value}").into());
+            }
+        } else if option.values == ["true"] {
+            layered.push(flag.into());
+        }
+        from_file.push(option.id);
+    }
+    layered.extend(args.into_iter().skip(1));
+
+    Ok((command.try_get_matches_from(layered)?, from_file))
+}
+
+/// Returns the effective configuration in TOML format with secrets masked. Comments show the
+/// source of each value.
+pub fn check(args: impl IntoIterator<Item = impl Into<OsString>>) -> Result<String, clap::Error> {
+    let (matches, from_file) = layered_matches(args)?;
+    let cli = Cli::from_arg_matches(&matches)?;
+    let mut command = Cli::command();
+    let config_file = match &cli.config {
+        Some(path) => ConfigFile::read(path),
+        None => Ok(ConfigFile::default()),
+    }
+    .map_err(|err| command.error(ErrorKind::Io, err))?;
+    let properties = kafka::properties(
+        &config_file,
+        kafka::env_properties(std::env::vars()),
+        &cli.kafka_properties,
+    )
+    .map_err(|err| command.error(ErrorKind::InvalidValue, err))?;
+
+    let mut output = String::new();
+    for (section, ids) in SECTIONS {
+        let _ = writeln!(output, "[{section}]");
+        for id in ids {
+            let Some(arg) = command.get_arguments().find(|arg| arg.get_id() == *id) else {
+                continue;
+            };
+            let Some(values) = matches.get_raw(id) else {
+                continue;
+            };
+            let mut values =
+                values.map(|value| toml::Value::from(value.to_string_lossy().as_ref()));
+            let value = if SECRETS.contains(id) {
+                toml::Value::from(MASK)
+            } else if !arg.get_action().takes_values() {
+                toml::Value::from(values.any(|value| value.as_str() == Some("true")))
+            } else if matches!(arg.get_action(), ArgAction::Append) {
+                toml::Value::Array(values.collect())
+            } else {
+                values.next().unwrap_or_else(|| toml::Value::from(""))
+            };
+            let source = if from_file.iter().any(|option| option == id) {
+                "config file".to_string()
+            } else {
+                match matches.value_source(id) {
+                    Some(ValueSource::EnvVariable) => format!(
+                        "env {}",
+                        arg.get_env().unwrap_or_default().to_string_lossy()
+                    ),
+                    Some(ValueSource::CommandLine) => "command line".to_string(),
+                    _ => "default".to_string(),
+                }
+            };
+            let _ = writeln!(output, "{id} = {value} # {source}");
+        }
+        if section == "kafka" && !properties.is_empty() {
+            let _ = writeln!(output, "\n[kafka.properties]");
+            // Later properties take precedence
+            let properties = properties
+                .iter()
+                .map(|property| (property.key.as_str(), property.value.as_str()))
+                .collect::<BTreeMap<_, _>>();
+            for (key, value) in properties {
+                let value = if is_secret_property(key) { MASK } else { value };
+                let _ = writeln!(
+                    output,
+                    "{} = {}",
+                    toml::Value::from(key),
+                    toml::Value::from(value)
+                );
+            }
+        }
+        if section == "routing" {
+            for rule in &config_file.routing.rules {
+                let rule = toml::to_string(rule).map_err(|err| {
+                    command.error(
+                        ErrorKind::InvalidValue,
+                        format!("Invalid routing rule: {err}"),
+                    )
+                })?;
+                let _ = write!(output, "\n[[routing.rules]]\n{rule}");
+            }
+        }
+        output.push('\n');
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cli::{Cli, check, parse_from};
+    use crate::config::SECTIONS;
+    use clap::CommandFactory;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    #[allow(clippy::expect_used)]
+    fn config_file(content: &str) -> NamedTempFile {
+        let mut file = NamedTempFile::new().expect("temp file");
+        file.write_all(content.as_bytes()).expect("config written");
+        file
+    }
+
+    #[test]
+    fn should_assign_all_options_to_sections() {
+        let command = Cli::command();
+        let options = command
+            .get_arguments()
+            .map(|arg| arg.get_id().as_str())
+            .filter(|id| !["config", "kafka_properties", "help", "version"].contains(id))
+            .collect::<Vec<_>>();
+        let section_options = SECTIONS
+            .iter()
+            .flat_map(|(_, ids)| ids.iter().copied())
+            .collect::<Vec<_>>();
+
+        for id in &options {
+            assert!(section_options.contains(id), "{id} not in any section");
+        }
+        assert_eq!(options.len(), section_options.len());
+    }
+
+    #[test]
+    #[allow(clippy::expect_used)]
+    fn should_use_config_file_with_lowest_precedence() {
+        let file = config_file(
+            r#"
+            [auth]
+            token = "token-from-file"
+
+            [kafka]
+            bootstrap_server = "kafka-from-file:9094"
+            sasl_plaintext = true
+
+            [routing]
+            topic = "topic-from-file"
+            validation_rules = ["variant-reference=error", "specimen-reference=ignore"]
+            "#,
+        );
+        let path = file.path().to_string_lossy().to_string();
+
+        let cli = parse_from(["gateway", "--config", &path, "--topic", "topic-from-cli"])
+            .expect("valid configuration");
+
+        assert_eq!(cli.token, Some("token-from-file".to_string()));
+        assert_eq!(cli.bootstrap_server, "kafka-from-file:9094");
+        assert!(cli.sasl_plaintext);
+        assert_eq!(cli.topic, "topic-from-cli");
+        assert_eq!(cli.validation_rules.len(), 2);
+        assert_eq!(cli.listen, "[::]:3000");
+    }
+
+    #[test]
+    #[allow(clippy::expect_used)]
+    fn should_use_config_file_values_starting_with_hyphen() {
+        let file = config_file(
+            r#"
+            [auth]
+            token = "--secret-token"
+
+            [kafka]
+            sasl_password = "-secret"
+            "#,
+        );
+        let path = file.path().to_string_lossy().to_string();
+
+        let cli = parse_from(["gateway", "--config", &path]).expect("valid configuration");
+
+        assert_eq!(cli.token, Some("--secret-token".to_string()));
+        assert_eq!(cli.sasl_password, Some("-secret".to_string()));
+    }
+
+    #[test]
+    fn should_reject_invalid_config_file() {
+        let file = config_file("[routing]\nunknown = 1");
+        let path = file.path().to_string_lossy().to_string();
+
+        assert!(parse_from(["gateway", "--config", &path]).is_err());
+    }
+
+    #[test]
+    #[allow(clippy::expect_used)]
+    fn should_print_effective_configuration_with_masked_secrets() {
+        let file = config_file(
+            r#"
+            [auth]
+            token = "secret-token"
+
+            [kafka]
+            sasl_password = "secret-password"
+
+            [kafka.properties]
+            "acks" = "all"
+            "sasl.password" = "secret-password"
+
+            [[routing.rules]]
+            topic = "deletions"
+            metadata = false
+            "#,
+        );
+        let path = file.path().to_string_lossy().to_string();
+
+        let config = check([
+            "gateway",
+            "--config",
+            &path,
+            "--topic",
+            "test-topic",
+            "config",
+            "check",
+        ])
+        .expect("valid configuration");
+
+        assert!(!config.contains("secret-"));
+        assert!(config.contains("token = \"********\" # config file\n"));
+        assert!(config.contains("sasl_password = \"********\" # config file\n"));
+        assert!(config.contains("listen = \"[::]:3000\" # default\n"));
+        assert!(config.contains("topic = \"test-topic\" # command line\n"));
+        assert!(config.contains("\n[kafka.properties]\n\"acks\" = \"all\"\n"));
+        assert!(config.contains("\"sasl.password\" = \"********\"\n"));
+        assert!(config.contains("\n[[routing.rules]]\ntopic = \"deletions\"\nmetadata = false\n"));
+    }
 }
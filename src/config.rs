@@ -0,0 +1,327 @@
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use crate::routing::RoutingRule;
+
+/// Options of each section of the configuration file, named like the options of the command line
+/// using underscores, e.g. `bootstrap_server` for `--bootstrap-server`
+pub const SECTIONS: [(&str, &[&str]); 4] = [
+    (
+        "listener",
+        &[
+            "listen",
+            "metrics_listen",
+            "otlp_endpoint",
+            "otlp_service_name",
+            "tls_cert_file",
+            "tls_key_file",
+            "tls_client_ca_file",
+            "tls_client_allow",
+            "tls_client_auth",
+            "max_body_size",
+            "max_bulk_body_size",
+            "max_decompressed_size",
+        ],
+    ),
+    (
+        "auth",
+        &[
+            "token",
+            "token_file",
+            "users_file",
+            "oidc_jwks_file",
+            "oidc_jwks_url",
+            "oidc_jwks_cache_ttl",
+            "oidc_issuer",
+            "oidc_audience",
+            "oidc_post_scope",
+            "oidc_delete_scope",
+        ],
+    ),
+    (
+        "kafka",
+        &[
+            "bootstrap_server",
+            "ssl_ca_file",
+            "ssl_cert_file",
+            "ssl_key_file",
+            "ssl_key_password",
+            "ssl_key_password_file",
+            "sasl_mechanism",
+            "sasl_plaintext",
+            "sasl_username",
+            "sasl_password",
+            "sasl_password_file",
+            "sasl_oauth_token_endpoint",
+            "sasl_oauth_client_id",
+            "sasl_oauth_client_secret",
+            "sasl_oauth_client_secret_file",
+            "sasl_oauth_scope",
+            "response_group_id",
+            "transactional_id",
+            "max_transaction_size",
+        ],
+    ),
+    (
+        "routing",
+        &[
+            "topic",
+            "response_topic",
+            "request_status_file",
+            "wait_for_delivery",
+            "idempotency_ttl",
+            "idempotency_content_hash",
+            "request_id_ttl",
+            "source_headers",
+            "unchanged_store_file",
+            "outbox_dir",
+            "outbox_max_size",
+            "outbox_fsync",
+            "validation_rules",
+            "pseudonymizer",
+            "pseudonym_hmac_key",
+            "pseudonym_hmac_key_file",
+            "pseudonym_mapping_file",
+            "gpas_url",
+            "gpas_domain",
+            "gpas_username",
+            "gpas_password",
+            "gpas_password_file",
+        ],
+    ),
+];
+
+/// Options containing secrets, masked when printing the configuration
+pub const SECRETS: [&str; 6] = [
+    "token",
+    "ssl_key_password",
+    "sasl_password",
+    "sasl_oauth_client_secret",
+    "pseudonym_hmac_key",
+    "gpas_password",
+];
+
+/// Kafka properties containing secrets or private keys, masked when printing the configuration.
+/// Entries ending with `*` match all properties starting with the entry.
+const SECRET_PROPERTIES: [&str; 7] = [
+    "ssl.key.password",
+    "ssl.key.pem",
+    "ssl_key",
+    "ssl.keystore.*",
+    "sasl.password",
+    "sasl.oauthbearer.config",
+    "sasl.oauthbearer.assertion.private.key.*",
+];
+
+/// Whether the Kafka property contains a secret, including unknown properties named like one
+pub fn is_secret_property(key: &str) -> bool {
+    key.contains("password")
+        || key.contains("secret")
+        || SECRET_PROPERTIES
+            .iter()
+            .any(|secret| match secret.strip_suffix('*') {
+                Some(prefix) => key.starts_with(prefix),
+                None => key == *secret,
+            })
+}
+
+pub type Section = BTreeMap<String, toml::Value>;
+
+/// Configuration file in TOML format
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    #[serde(default)]
+    pub listener: Section,
+    #[serde(default)]
+    pub auth: Section,
+    #[serde(default)]
+    pub kafka: KafkaSection,
+    #[serde(default)]
+    pub routing: RoutingSection,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct KafkaSection {
+    /// Properties passed to librdkafka
+    #[serde(default)]
+    pub properties: BTreeMap<String, toml::Value>,
+    #[serde(flatten)]
+    pub options: Section,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct RoutingSection {
+    /// Rules selecting the topic, the first matching one is used
+    #[serde(default)]
+    pub rules: Vec<RoutingRule>,
+    #[serde(flatten)]
+    pub options: Section,
+}
+
+/// Option of the configuration file with its values as used on the command line
+#[derive(Debug, PartialEq, Eq)]
+pub struct ConfigOption {
+    pub id: String,
+    pub values: Vec<String>,
+}
+
+impl ConfigFile {
+    pub fn read(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path)
+            .map_err(|err| format!("Cannot read config file '{}': {err}", path.display()))?;
+        Self::parse(&content)
+            .map_err(|err| format!("Invalid config file '{}': {err}", path.display()))
+    }
+
+    pub fn parse(content: &str) -> Result<Self, String> {
+        let config_file: Self = toml::from_str(content).map_err(|err| err.to_string())?;
+        config_file.options()?;
+        Ok(config_file)
+    }
+
+    fn section(&self, name: &str) -> &Section {
+        match name {
+            "listener" => &self.listener,
+            "auth" => &self.auth,
+            "kafka" => &self.kafka.options,
+            _ => &self.routing.options,
+        }
+    }
+
+    /// Returns all options of all sections
+    pub fn options(&self) -> Result<Vec<ConfigOption>, String> {
+        let mut options = vec![];
+        for (name, ids) in SECTIONS {
+            for (id, value) in self.section(name) {
+                if !ids.contains(&id.as_str()) {
+                    return Err(format!("Unknown option '{id}' in section [{name}]"));
+                }
+                let values = match value {
+                    toml::Value::Array(values) => values.iter().map(scalar).collect(),
+                    value => scalar(value).map(|value| vec![value]),
+                }
+                .map_err(|()| format!("Invalid value of option '{id}' in section [{name}]"))?;
+                options.push(ConfigOption {
+                    id: id.clone(),
+                    values,
+                });
+            }
+        }
+        Ok(options)
+    }
+}
+
+fn scalar(value: &toml::Value) -> Result<String, ()> {
+    match value {
+        toml::Value::String(value) => Ok(value.clone()),
+        toml::Value::Integer(value) => Ok(value.to_string()),
+        toml::Value::Float(value) => Ok(value.to_string()),
+        toml::Value::Boolean(value) => Ok(value.to_string()),
+        _ => Err(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{ConfigFile, ConfigOption, is_secret_property};
+
+    #[test]
+    #[allow(clippy::expect_used)]
+    fn should_parse_kafka_properties() {
+        let config = ConfigFile::parse(
+            r#"
+            [kafka.properties]
+            "acks" = "all"
+            "enable.idempotence" = true
+            "linger.ms" = 10
+            "#,
+        )
+        .expect("valid config");
+
+        assert_eq!(config.kafka.properties.len(), 3);
+        assert_eq!(
+            config.kafka.properties.get("linger.ms"),
+            Some(&toml::Value::Integer(10))
+        );
+    }
+
+    #[test]
+    #[allow(clippy::expect_used)]
+    fn should_return_options_of_all_sections() {
+        let config = ConfigFile::parse(
+            r#"
+            [listener]
+            listen = "127.0.0.1:3000"
+            tls_client_allow = ["CN=client1", "CN=client2"]
+
+            [auth]
+            oidc_jwks_cache_ttl = 60
+
+            [kafka]
+            bootstrap_server = "kafka1:9094,kafka2:9094"
+            sasl_plaintext = true
+
+            [kafka.properties]
+            "acks" = "all"
+
+            [routing]
+            topic = "test-topic"
+
+            [[routing.rules]]
+            topic = "deletions"
+            metadata = false
+            "#,
+        )
+        .expect("valid config");
+
+        let option = |id: &str, values: &[&str]| ConfigOption {
+            id: id.to_string(),
+            values: values.iter().map(ToString::to_string).collect(),
+        };
+        assert_eq!(
+            config.options(),
+            Ok(vec![
+                option("listen", &["127.0.0.1:3000"]),
+                option("tls_client_allow", &["CN=client1", "CN=client2"]),
+                option("oidc_jwks_cache_ttl", &["60"]),
+                option("bootstrap_server", &["kafka1:9094,kafka2:9094"]),
+                option("sasl_plaintext", &["true"]),
+                option("topic", &["test-topic"]),
+            ])
+        );
+    }
+
+    #[test]
+    fn should_reject_unknown_sections() {
+        assert!(ConfigFile::parse("[unknown]\nkey = 1").is_err());
+    }
+
+    #[test]
+    fn should_reject_options_in_wrong_section() {
+        assert_eq!(
+            ConfigFile::parse("[listener]\ntopic = \"test-topic\"").map(|_| ()),
+            Err("Unknown option 'topic' in section [listener]".to_string())
+        );
+    }
+
+    #[test]
+    fn should_detect_secret_kafka_properties() {
+        for key in [
+            "ssl.key.pem",
+            "ssl.key.password",
+            "ssl.keystore.location",
+            "sasl.password",
+            "sasl.oauthbearer.config",
+            "sasl.oauthbearer.client.secret",
+            "sasl.oauthbearer.assertion.private.key.pem",
+        ] {
+            assert!(is_secret_property(key), "{key} not masked");
+        }
+        assert!(!is_secret_property("ssl.certificate.pem"));
+        assert!(!is_secret_property("acks"));
+    }
+}
@@ -0,0 +1,95 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// Values by key, retained for the TTL. Beyond the capacity, the oldest entries are removed
+/// even if not expired yet.
+pub struct ExpiringMap<V> {
+    ttl: Duration,
+    capacity: usize,
+    entries: HashMap<String, (u64, V)>,
+    // Using the same TTL for all entries, entries expire in insertion order
+    order: VecDeque<(String, u64, Instant)>,
+    inserted: u64,
+}
+
+impl<V> ExpiringMap<V> {
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            ttl,
+            capacity,
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+            inserted: 0,
+        }
+    }
+
+    /// Returns the value of the key unless expired
+    pub fn get(&mut self, key: &str) -> Option<&V> {
+        self.expire(Instant::now());
+        self.entries.get(key).map(|(_, value)| value)
+    }
+
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut V> {
+        self.entries.get_mut(key).map(|(_, value)| value)
+    }
+
+    pub fn insert(&mut self, key: String, value: V) {
+        let now = Instant::now();
+        self.inserted += 1;
+        self.order
+            .push_back((key.clone(), self.inserted, now + self.ttl));
+        self.entries.insert(key, (self.inserted, value));
+        self.expire(now);
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        self.entries.remove(key);
+    }
+
+    /// Removes expired entries and the oldest entries beyond the capacity
+    fn expire(&mut self, now: Instant) {
+        while let Some((key, inserted, expires)) = self.order.front() {
+            if *expires > now && self.order.len() <= self.capacity {
+                break;
+            }
+            // The key may have been removed and inserted again since
+            if self
+                .entries
+                .get(key)
+                .is_some_and(|(entry_inserted, _)| entry_inserted == inserted)
+            {
+                self.entries.remove(key);
+            }
+            self.order.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::expiring::ExpiringMap;
+    use std::time::Duration;
+
+    #[test]
+    fn should_remove_expired_entries() {
+        let mut entries = ExpiringMap::new(Duration::ZERO, 10);
+        entries.insert("key".to_string(), 1);
+        assert_eq!(entries.get("key"), None);
+    }
+
+    #[test]
+    fn should_remove_oldest_entries_beyond_capacity() {
+        let mut entries = ExpiringMap::new(Duration::from_mins(1), 2);
+        entries.insert("a".to_string(), 1);
+        entries.insert("b".to_string(), 2);
+        entries.remove("b");
+        entries.insert("b".to_string(), 3);
+        assert_eq!(entries.get("a"), None);
+        assert_eq!(entries.get("b"), Some(&3));
+
+        entries.insert("c".to_string(), 4);
+        assert_eq!(entries.get("b"), Some(&3));
+        assert_eq!(entries.get("c"), Some(&4));
+        assert_eq!(entries.order.len(), 2);
+    }
+}
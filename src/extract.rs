@@ -0,0 +1,330 @@
+use axum::extract::{FromRequest, Request};
+use axum::response::{IntoResponse, Response};
+use http_body_util::BodyExt;
+use mv64e_mtb_dto::Mtb;
+use serde_json::{Map, Value};
+use serde_path_to_error::Segment;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::limit;
+use crate::problem::{FieldError, Problem, ProblemType};
+use crate::request_id::with_client_request_id;
+
+const MAX_FIELD_ERRORS: usize = 100;
+const MAX_ATTEMPTS: usize = 500;
+const MAX_PLACEHOLDER_ATTEMPTS: usize = 3;
+/// Bytes of request bodies deserialized again at most to find further offending fields
+const MAX_REPEATED_BYTES: usize = 8 * 1024 * 1024;
+
+/// Limits the work of finding offending fields of invalid MTB files, shared by all MTB files
+/// of a request. If used up, only the first offending field is reported.
+pub struct ErrorSearchBudget(AtomicUsize);
+
+impl ErrorSearchBudget {
+    pub fn new(bytes: usize) -> Self {
+        Self(AtomicUsize::new(bytes))
+    }
+
+    fn take(&self, bytes: usize) -> bool {
+        self.0
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |remaining| {
+                remaining.checked_sub(bytes)
+            })
+            .is_ok()
+    }
+}
+
+impl Default for ErrorSearchBudget {
+    fn default() -> Self {
+        Self::new(MAX_REPEATED_BYTES)
+    }
+}
+
+/// Extracts an MTB file from the JSON request body.
+///
+/// Rejects the request with problem details listing every offending field.
+pub struct MtbFile(pub Mtb);
+
+impl<S> FromRequest<S> for MtbFile
+where
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request(request: Request, _state: &S) -> Result<Self, Self::Rejection> {
+        let headers = request.headers().clone();
+        // Body size is limited by middleware, see `limit`
+        let body = request
+            .into_body()
+            .collect()
+            .await
+            .map_err(|err| match limit::exceeded(&err) {
+                Some(exceeded) => exceeded.problem(),
+                None => Problem::new(ProblemType::InvalidJson)
+                    .with_detail(&format!("Cannot read request body: {err}")),
+            })
+            .map_err(|problem| with_client_request_id(problem, &headers).into_response())?
+            .to_bytes();
+        deserialize_mtb(&body)
+            .map(MtbFile)
+            .map_err(|problem| with_client_request_id(problem, &headers).into_response())
+    }
+}
+
+pub fn deserialize_mtb(body: &[u8]) -> Result<Mtb, Problem> {
+    deserialize_mtb_within(body, &ErrorSearchBudget::default())
+}
+
+pub fn deserialize_mtb_within(body: &[u8], budget: &ErrorSearchBudget) -> Result<Mtb, Problem> {
+    let mut value = serde_json::from_slice::<Value>(body)
+        .map_err(|err| Problem::new(ProblemType::InvalidJson).with_detail(&err.to_string()))?;
+
+    // Serde stops at the first error. To find all offending fields, each one found is
+    // replaced by a placeholder of the expected type before deserializing the document again.
+    let mut errors = vec![];
+    let mut placeholders: Vec<Vec<PathSegment>> = vec![];
+    for _ in 0..MAX_ATTEMPTS {
+        let err = match serde_path_to_error::deserialize::<_, Mtb>(&value) {
+            Ok(mtb) if errors.is_empty() => return Ok(mtb),
+            Ok(_) => break,
+            Err(err) => err,
+        };
+
+        let mut segments = err.path().iter().cloned().collect::<Vec<_>>();
+        let message = err.inner().to_string();
+
+        let replacement = if let Some(field) = missing_field(&message) {
+            segments.push(Segment::Map {
+                key: field.to_string(),
+            });
+            Value::Null
+        } else if let Some(placeholder) = placeholder(&message) {
+            placeholder
+        } else {
+            Value::Null
+        };
+
+        let path = path(&segments);
+        if !placeholders
+            .iter()
+            .any(|placeholder| path.starts_with(placeholder))
+        {
+            errors.push(FieldError {
+                path: format_path(&path),
+                message,
+            });
+        }
+
+        // Stop if no placeholder matching the expected type can be found
+        let attempts = placeholders
+            .iter()
+            .filter(|placeholder| **placeholder == path)
+            .count();
+        if errors.len() >= MAX_FIELD_ERRORS
+            || attempts >= MAX_PLACEHOLDER_ATTEMPTS
+            || !replace(&mut value, &segments, replacement)
+            || !budget.take(body.len())
+        {
+            break;
+        }
+        placeholders.push(path);
+    }
+
+    Err(Problem::new(ProblemType::InvalidMtbFile)
+        .with_detail("The request body does not contain a valid MTB file")
+        .with_errors(errors))
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum PathSegment {
+    Index(usize),
+    Key(String),
+}
+
+fn path(segments: &[Segment]) -> Vec<PathSegment> {
+    segments
+        .iter()
+        .filter_map(|segment| match segment {
+            Segment::Seq { index } => Some(PathSegment::Index(*index)),
+            Segment::Map { key } | Segment::Enum { variant: key } => {
+                Some(PathSegment::Key(key.clone()))
+            }
+            Segment::Unknown => None,
+        })
+        .collect()
+}
+
+fn format_path(path: &[PathSegment]) -> String {
+    path.iter()
+        .fold("$".to_string(), |result, segment| match segment {
+            PathSegment::Index(index) => format!("{result}[{index}]"),
+            PathSegment::Key(key) => format!("{result}.{key}"),
+        })
+}
+
+fn missing_field(message: &str) -> Option<&str> {
+    message.strip_prefix("missing field `")?.strip_suffix('`')
+}
+
+/// Returns a value of the type expected according to the serde error message
+fn placeholder(message: &str) -> Option<Value> {
+    let (_, expected) = message.rsplit_once("expected ")?;
+
+    if let Some((_, variant)) = expected.split_once('`') {
+        return variant
+            .split_once('`')
+            .map(|(variant, _)| Value::String(variant.to_string()));
+    }
+
+    let expected = expected.trim_start_matches("a ").trim_start_matches("an ");
+    let placeholder = match expected {
+        "boolean" => Value::Bool(false),
+        "sequence" => Value::Array(vec![]),
+        "f32" | "f64" => serde_json::json!(0.0),
+        "map" => Value::Object(Map::new()),
+        _ if expected.starts_with("struct ") => Value::Object(Map::new()),
+        "i8" | "i16" | "i32" | "i64" | "i128" | "isize" | "u8" | "u16" | "u32" | "u64" | "u128"
+        | "usize" => serde_json::json!(0),
+        _ if expected.contains("string") => Value::String(String::new()),
+        _ => return None,
+    };
+    Some(placeholder)
+}
+
+fn replace(value: &mut Value, segments: &[Segment], replacement: Value) -> bool {
+    let Some((last, parents)) = segments.split_last() else {
+        return false;
+    };
+
+    let mut current = value;
+    for segment in parents {
+        let next = match (segment, current) {
+            (Segment::Seq { index }, Value::Array(items)) => items.get_mut(*index),
+            (Segment::Map { key } | Segment::Enum { variant: key }, Value::Object(fields)) => {
+                fields.get_mut(key)
+            }
+            _ => None,
+        };
+        let Some(next) = next else {
+            return false;
+        };
+        current = next;
+    }
+
+    let (target, inserted) = match (last, current) {
+        (Segment::Seq { index }, Value::Array(items)) => (items.get_mut(*index), false),
+        (Segment::Map { key } | Segment::Enum { variant: key }, Value::Object(fields)) => {
+            let inserted = !fields.contains_key(key);
+            (
+                Some(fields.entry(key.clone()).or_insert(Value::Null)),
+                inserted,
+            )
+        }
+        _ => (None, false),
+    };
+
+    match target {
+        // Replacing a value by itself would not change the result
+        Some(target) if inserted || *target != replacement => {
+            *target = replacement;
+            true
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::extract::{ErrorSearchBudget, deserialize_mtb, deserialize_mtb_within};
+    use crate::problem::FieldError;
+    use axum::http::StatusCode;
+
+    #[test]
+    fn should_deserialize_valid_mtb_file() {
+        let body = include_str!("../test-files/mv64e-mtb-fake-patient.json");
+        assert!(deserialize_mtb(body.as_bytes()).is_ok());
+    }
+
+    #[test]
+    #[allow(clippy::unwrap_used)]
+    fn should_reject_invalid_json() {
+        let problem = deserialize_mtb(b"Das ist kein JSON!")
+            .map(|_| ())
+            .unwrap_err();
+        assert_eq!(problem.status, StatusCode::BAD_REQUEST.as_u16());
+        assert!(problem.errors.is_empty());
+    }
+
+    #[test]
+    #[allow(clippy::unwrap_used)]
+    fn should_report_missing_field() {
+        let problem = deserialize_mtb(b"{}").map(|_| ()).unwrap_err();
+        assert_eq!(problem.status, StatusCode::UNPROCESSABLE_ENTITY.as_u16());
+        assert_eq!(
+            problem.errors,
+            vec![FieldError {
+                path: "$.patient".to_string(),
+                message: "missing field `patient`".to_string()
+            }]
+        );
+    }
+
+    #[test]
+    #[allow(clippy::unwrap_used)]
+    fn should_report_every_offending_field() {
+        let mut mtb = serde_json::from_str::<serde_json::Value>(include_str!(
+            "../test-files/mv64e-mtb-fake-patient.json"
+        ))
+        .unwrap();
+        mtb["patient"]["id"] = serde_json::json!(1);
+        mtb["diagnoses"] = serde_json::json!([
+            mtb["diagnoses"][0].clone(),
+            mtb["diagnoses"][0].clone(),
+            mtb["diagnoses"][0].clone()
+        ]);
+        mtb["diagnoses"][0]["id"] = serde_json::json!(2);
+        mtb["diagnoses"][2]["id"] = serde_json::json!(3);
+
+        let problem = deserialize_mtb(&serde_json::to_vec(&mtb).unwrap())
+            .map(|_| ())
+            .unwrap_err();
+        let mut paths = problem
+            .errors
+            .iter()
+            .map(|error| error.path.as_str())
+            .collect::<Vec<_>>();
+        paths.sort_unstable();
+
+        assert_eq!(
+            paths,
+            vec!["$.diagnoses[0].id", "$.diagnoses[2].id", "$.patient.id"]
+        );
+        assert!(problem.errors.contains(&FieldError {
+            path: "$.patient.id".to_string(),
+            message: "invalid type: integer `1`, expected a string".to_string()
+        }));
+    }
+
+    #[test]
+    #[allow(clippy::unwrap_used)]
+    fn should_report_first_offending_field_only_if_budget_is_used_up() {
+        let mut mtb = serde_json::from_str::<serde_json::Value>(include_str!(
+            "../test-files/mv64e-mtb-fake-patient.json"
+        ))
+        .unwrap();
+        mtb["patient"]["id"] = serde_json::json!(1);
+        mtb["diagnoses"][0]["id"] = serde_json::json!(2);
+        let body = serde_json::to_vec(&mtb).unwrap();
+
+        let budget = ErrorSearchBudget::new(body.len());
+        let problem = deserialize_mtb_within(&body, &budget)
+            .map(|_| ())
+            .unwrap_err();
+        assert_eq!(problem.errors.len(), 2);
+
+        let problem = deserialize_mtb_within(&body, &budget)
+            .map(|_| ())
+            .unwrap_err();
+        assert_eq!(problem.errors.len(), 1);
+    }
+}
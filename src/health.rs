@@ -0,0 +1,267 @@
+use async_trait::async_trait;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Extension, Json, Router};
+use rdkafka::producer::{FutureProducer, Producer};
+use serde::Serialize;
+use serde_json::json;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+#[cfg(test)]
+use mockall::automock;
+
+use crate::CONFIG;
+use crate::kafka::KafkaContext;
+use crate::outbox::Outbox;
+
+const METADATA_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub type DynKafkaProbe = Arc<dyn KafkaProbe + Send + Sync>;
+
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct KafkaStatus {
+    pub reachable: bool,
+    pub brokers: usize,
+    pub topic: String,
+    pub topic_exists: bool,
+    /// Whether the producer failed and must be restarted, e.g. if fenced by another producer
+    pub producer_failed: bool,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct OutboxStatus {
+    size: u64,
+    max_size: u64,
+}
+
+#[cfg_attr(test, automock)]
+#[async_trait]
+pub trait KafkaProbe {
+    /// Fetches broker metadata of the topic
+    async fn probe(&self, topic: &str) -> KafkaStatus;
+}
+
+/// Uses the client of the producer to fetch broker metadata
+pub struct ProducerProbe {
+    producer: FutureProducer<KafkaContext>,
+    failed: Arc<AtomicBool>,
+}
+
+impl ProducerProbe {
+    pub fn new(producer: FutureProducer<KafkaContext>) -> Self {
+        Self {
+            producer,
+            failed: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Flag to be set by senders if the producer failed
+    pub fn failed(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.failed)
+    }
+}
+
+#[async_trait]
+impl KafkaProbe for ProducerProbe {
+    async fn probe(&self, topic: &str) -> KafkaStatus {
+        let producer = self.producer.clone();
+        let topic = topic.to_string();
+        let producer_failed = self.failed.load(Ordering::Relaxed);
+        // Fetching metadata blocks until the broker responds or timeout
+        tokio::task::spawn_blocking(move || {
+            match producer
+                .client()
+                .fetch_metadata(Some(&topic), METADATA_TIMEOUT)
+            {
+                Ok(metadata) => KafkaStatus {
+                    reachable: true,
+                    brokers: metadata.brokers().len(),
+                    topic_exists: metadata.topics().iter().any(|metadata_topic| {
+                        metadata_topic.name() == topic
+                            && metadata_topic.error().is_none()
+                            && !metadata_topic.partitions().is_empty()
+                    }),
+                    topic,
+                    producer_failed,
+                },
+                Err(err) => {
+                    log::warn!("Cannot fetch Kafka metadata: {err}");
+                    KafkaStatus {
+                        topic,
+                        producer_failed,
+                        ..KafkaStatus::default()
+                    }
+                }
+            }
+        })
+        .await
+        .unwrap_or_default()
+    }
+}
+
+async fn handle_live() -> Response {
+    Json(json!({ "status": "UP" })).into_response()
+}
+
+/// Ready if Kafka can be used or requests can be stored in the outbox, unless the producer failed
+async fn handle_ready(
+    Extension(probe): Extension<DynKafkaProbe>,
+    outbox: Option<Extension<Arc<Outbox>>>,
+) -> Response {
+    let kafka = probe.probe(&CONFIG.topic).await;
+    let outbox = outbox.map(|Extension(outbox)| OutboxStatus {
+        size: outbox.size(),
+        max_size: outbox.max_size(),
+    });
+
+    let ready = !kafka.producer_failed
+        && ((kafka.reachable && kafka.topic_exists)
+            || outbox
+                .as_ref()
+                .is_some_and(|outbox| outbox.size < outbox.max_size));
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (
+        status,
+        Json(json!({
+            "status": if ready { "UP" } else { "DOWN" },
+            "kafka": kafka,
+            "outbox": outbox,
+        })),
+    )
+        .into_response()
+}
+
+pub fn routes(probe: DynKafkaProbe, outbox: Option<Arc<Outbox>>) -> Router {
+    let router = Router::new()
+        .route("/health/live", get(handle_live))
+        .route("/health/ready", get(handle_ready))
+        .layer(Extension(probe));
+    match outbox {
+        Some(outbox) => router.layer(Extension(outbox)),
+        None => router,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::health::{DynKafkaProbe, KafkaStatus, MockKafkaProbe, routes};
+    use crate::outbox::{FsyncPolicy, Outbox};
+    use axum::Router;
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use http_body_util::BodyExt;
+    use serde_json::Value;
+    use std::sync::Arc;
+    use tower::ServiceExt;
+
+    fn probe(reachable: bool, topic_exists: bool) -> DynKafkaProbe {
+        probe_with_failed_producer(reachable, topic_exists, false)
+    }
+
+    fn probe_with_failed_producer(
+        reachable: bool,
+        topic_exists: bool,
+        producer_failed: bool,
+    ) -> DynKafkaProbe {
+        let mut probe = MockKafkaProbe::new();
+        probe.expect_probe().returning(move |topic| KafkaStatus {
+            reachable,
+            brokers: usize::from(reachable),
+            topic: topic.to_string(),
+            topic_exists,
+            producer_failed,
+        });
+        Arc::new(probe)
+    }
+
+    #[allow(clippy::expect_used)]
+    async fn get(router: Router, uri: &str) -> (StatusCode, Value) {
+        let response = router
+            .oneshot(
+                Request::builder()
+                    .uri(uri)
+                    .body(Body::empty())
+                    .expect("request built"),
+            )
+            .await
+            .expect("response");
+        let status = response.status();
+        let body = response
+            .into_body()
+            .collect()
+            .await
+            .expect("body collected")
+            .to_bytes();
+        (status, serde_json::from_slice(&body).expect("JSON body"))
+    }
+
+    #[tokio::test]
+    async fn should_be_live_without_kafka() {
+        let (status, body) = get(routes(probe(false, false), None), "/health/live").await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["status"], "UP");
+    }
+
+    #[tokio::test]
+    async fn should_be_ready() {
+        let (status, body) = get(routes(probe(true, true), None), "/health/ready").await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["status"], "UP");
+        assert_eq!(body["kafka"]["brokers"], 1);
+        assert_eq!(body["kafka"]["topic"], "test-topic");
+        assert_eq!(body["kafka"]["topicExists"], true);
+        assert_eq!(body["outbox"], Value::Null);
+    }
+
+    #[tokio::test]
+    async fn should_not_be_ready_without_topic() {
+        let (status, body) = get(routes(probe(true, false), None), "/health/ready").await;
+
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["status"], "DOWN");
+        assert_eq!(body["kafka"]["reachable"], true);
+    }
+
+    #[tokio::test]
+    async fn should_not_be_ready_if_producer_failed() {
+        let (status, body) = get(
+            routes(probe_with_failed_producer(true, true, true), None),
+            "/health/ready",
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["status"], "DOWN");
+        assert_eq!(body["kafka"]["producerFailed"], true);
+    }
+
+    #[tokio::test]
+    #[allow(clippy::expect_used)]
+    async fn should_be_ready_using_outbox() {
+        let dir = tempfile::tempdir().expect("temp dir");
+        let outbox = Outbox::open(dir.path(), 1024, FsyncPolicy::Never).expect("outbox opened");
+
+        let (status, body) = get(
+            routes(probe(false, false), Some(Arc::new(outbox))),
+            "/health/ready",
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["kafka"]["reachable"], false);
+        assert_eq!(body["outbox"]["size"], 0);
+        assert_eq!(body["outbox"]["maxSize"], 1024);
+    }
+}
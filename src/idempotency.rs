@@ -0,0 +1,232 @@
+use axum::http::{HeaderMap, HeaderName};
+use mv64e_mtb_dto::Mtb;
+use sha2::{Digest, Sha256};
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::expiring::ExpiringMap;
+
+pub const IDEMPOTENCY_KEY: HeaderName = HeaderName::from_static("idempotency-key");
+pub const IDEMPOTENT_REPLAYED: HeaderName = HeaderName::from_static("idempotent-replayed");
+
+const MAX_KEY_LENGTH: usize = 255;
+const MAX_ENTRIES: usize = 100_000;
+
+pub type ContentHash = [u8; 32];
+
+enum State {
+    InProgress,
+    Completed(String),
+}
+
+struct Entry {
+    hash: ContentHash,
+    state: State,
+}
+
+/// Result of starting a request using an idempotency key
+#[derive(Debug, PartialEq, Eq)]
+pub enum Idempotency {
+    /// First request using the key, to be completed or released
+    New,
+    /// Repeated request already accepted with the request ID
+    Replayed(String),
+    /// Key already used for a different MTB file
+    Conflict,
+    /// Request using the same key not completed yet
+    InProgress,
+}
+
+/// Request IDs of accepted MTB files by idempotency key, retained for the configured TTL.
+/// At most 100,000 keys are retained.
+pub struct IdempotencyStore {
+    content_hash: bool,
+    entries: Mutex<ExpiringMap<Entry>>,
+}
+
+impl IdempotencyStore {
+    pub fn new(ttl: Duration, content_hash: bool) -> Self {
+        Self {
+            content_hash,
+            entries: Mutex::new(ExpiringMap::new(ttl, MAX_ENTRIES)),
+        }
+    }
+
+    /// Returns the key of the user given by `Idempotency-Key` header or, if enabled, derived from
+    /// the content hash of the MTB file
+    pub fn key(
+        &self,
+        headers: &HeaderMap,
+        username: &str,
+        hash: &ContentHash,
+    ) -> Result<Option<String>, String> {
+        match headers.get(IDEMPOTENCY_KEY) {
+            Some(value) => match value.to_str() {
+                Ok(key) if !key.is_empty() && key.len() <= MAX_KEY_LENGTH => {
+                    Ok(Some(format!("{username}:key:{key}")))
+                }
+                _ => Err(format!(
+                    "The idempotency key must contain 1 to {MAX_KEY_LENGTH} visible ASCII characters"
+                )),
+            },
+            None if self.content_hash => Ok(Some(format!(
+                "{username}:sha256:{}",
+                hash.iter().fold(String::new(), |mut hex, byte| {
+                    let _ = write!(hex, "{byte:02x}");
+                    hex
+                })
+            ))),
+            None => Ok(None),
+        }
+    }
+
+    pub fn begin(&self, key: &str, hash: ContentHash) -> Idempotency {
+        let Ok(mut entries) = self.entries.lock() else {
+            return Idempotency::New;
+        };
+        match entries.get(key) {
+            Some(entry) if entry.hash != hash => Idempotency::Conflict,
+            Some(Entry {
+                state: State::Completed(request_id),
+                ..
+            }) => Idempotency::Replayed(request_id.clone()),
+            Some(_) => Idempotency::InProgress,
+            None => {
+                entries.insert(
+                    key.to_string(),
+                    Entry {
+                        hash,
+                        state: State::InProgress,
+                    },
+                );
+                Idempotency::New
+            }
+        }
+    }
+
+    /// Remembers the request ID of the accepted MTB file
+    fn complete(&self, key: &str, request_id: &str) {
+        if let Ok(mut entries) = self.entries.lock()
+            && let Some(entry) = entries.get_mut(key)
+        {
+            entry.state = State::Completed(request_id.to_string());
+        }
+    }
+
+    /// Releases the key of a failed request, allowing to retry it
+    fn release(&self, key: &str) {
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.remove(key);
+        }
+    }
+}
+
+/// Key of a started request. Unless completed, the key is released when dropped, e.g. if the
+/// client disconnects before the MTB file has been sent, allowing to retry the request.
+pub struct IdempotencyGuard {
+    store: Arc<IdempotencyStore>,
+    key: Option<String>,
+}
+
+impl IdempotencyGuard {
+    pub fn new(store: Arc<IdempotencyStore>, key: String) -> Self {
+        Self {
+            store,
+            key: Some(key),
+        }
+    }
+
+    /// Remembers the request ID of the accepted MTB file
+    pub fn complete(mut self, request_id: &str) {
+        if let Some(key) = self.key.take() {
+            self.store.complete(&key, request_id);
+        }
+    }
+}
+
+impl Drop for IdempotencyGuard {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.store.release(&key);
+        }
+    }
+}
+
+/// Hash of the canonical JSON of the MTB file, serialized without whitespace and with sorted keys
+pub fn content_hash(mtb: &Mtb) -> ContentHash {
+    let canonical = serde_json::to_value(mtb).and_then(|value| serde_json::to_vec(&value));
+    Sha256::digest(canonical.unwrap_or_default()).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::idempotency::{IDEMPOTENCY_KEY, Idempotency, IdempotencyGuard, IdempotencyStore};
+    use axum::http::{HeaderMap, HeaderValue};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn should_replay_completed_request() {
+        let store = IdempotencyStore::new(Duration::from_mins(1), false);
+
+        assert_eq!(store.begin("key", [1; 32]), Idempotency::New);
+        assert_eq!(store.begin("key", [1; 32]), Idempotency::InProgress);
+        store.complete("key", "request-id");
+        assert_eq!(
+            store.begin("key", [1; 32]),
+            Idempotency::Replayed("request-id".to_string())
+        );
+        assert_eq!(store.begin("key", [2; 32]), Idempotency::Conflict);
+    }
+
+    #[test]
+    fn should_forget_released_and_expired_keys() {
+        let store = IdempotencyStore::new(Duration::from_mins(1), false);
+        assert_eq!(store.begin("key", [1; 32]), Idempotency::New);
+        store.release("key");
+        assert_eq!(store.begin("key", [2; 32]), Idempotency::New);
+
+        let store = IdempotencyStore::new(Duration::ZERO, false);
+        assert_eq!(store.begin("key", [1; 32]), Idempotency::New);
+        store.complete("key", "request-id");
+        assert_eq!(store.begin("key", [1; 32]), Idempotency::New);
+    }
+
+    #[test]
+    fn should_release_key_unless_completed() {
+        let store = Arc::new(IdempotencyStore::new(Duration::from_mins(1), false));
+
+        assert_eq!(store.begin("key", [1; 32]), Idempotency::New);
+        drop(IdempotencyGuard::new(Arc::clone(&store), "key".to_string()));
+        assert_eq!(store.begin("key", [1; 32]), Idempotency::New);
+
+        IdempotencyGuard::new(Arc::clone(&store), "key".to_string()).complete("request-id");
+        assert_eq!(
+            store.begin("key", [1; 32]),
+            Idempotency::Replayed("request-id".to_string())
+        );
+    }
+
+    #[test]
+    fn should_return_key_of_user() {
+        let mut headers = HeaderMap::new();
+        let store = IdempotencyStore::new(Duration::from_mins(1), false);
+        assert_eq!(store.key(&headers, "user", &[0; 32]), Ok(None));
+
+        let store = IdempotencyStore::new(Duration::from_mins(1), true);
+        assert_eq!(
+            store.key(&headers, "user", &[0xab; 32]),
+            Ok(Some(format!("user:sha256:{}", "ab".repeat(32))))
+        );
+
+        headers.insert(IDEMPOTENCY_KEY, HeaderValue::from_static("retry-1"));
+        assert_eq!(
+            store.key(&headers, "user", &[0xab; 32]),
+            Ok(Some("user:key:retry-1".to_string()))
+        );
+
+        headers.insert(IDEMPOTENCY_KEY, HeaderValue::from_static(""));
+        assert!(store.key(&headers, "user", &[0xab; 32]).is_err());
+    }
+}
@@ -0,0 +1,415 @@
+use clap::ValueEnum;
+use rdkafka::client::OAuthToken;
+use rdkafka::consumer::ConsumerContext;
+use rdkafka::error::KafkaError;
+use rdkafka::{ClientConfig, ClientContext, Statistics};
+use serde::Deserialize;
+use std::error::Error;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::runtime::Handle;
+
+use crate::CONFIG;
+use crate::config::ConfigFile;
+use crate::metrics::METRICS;
+use crate::secrets::{SECRETS, Secret, SecretValue};
+
+const ENV_PREFIX: &str = "KAFKA_PROP_";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum SaslMechanism {
+    Plain,
+    #[value(name = "scram-sha-256")]
+    ScramSha256,
+    #[value(name = "scram-sha-512")]
+    ScramSha512,
+    Oauthbearer,
+}
+
+impl SaslMechanism {
+    /// Name of the mechanism used by librdkafka
+    pub fn name(self) -> &'static str {
+        match self {
+            SaslMechanism::Plain => "PLAIN",
+            SaslMechanism::ScramSha256 => "SCRAM-SHA-256",
+            SaslMechanism::ScramSha512 => "SCRAM-SHA-512",
+            SaslMechanism::Oauthbearer => "OAUTHBEARER",
+        }
+    }
+}
+
+/// Sets SASL properties if a SASL mechanism is configured
+pub fn configure_sasl(client_config: &mut ClientConfig) -> Result<(), String> {
+    let Some(mechanism) = CONFIG.sasl_mechanism else {
+        return Ok(());
+    };
+
+    let protocol = if CONFIG.sasl_plaintext {
+        "sasl_plaintext"
+    } else {
+        "sasl_ssl"
+    };
+    client_config
+        .set("security.protocol", protocol)
+        .set("sasl.mechanism", mechanism.name());
+    if let Some(ssl_ca_file) = &CONFIG.ssl_ca_file {
+        client_config.set("ssl.ca.location", ssl_ca_file);
+    }
+
+    if mechanism != SaslMechanism::Oauthbearer {
+        let Some(username) = &CONFIG.sasl_username else {
+            return Err(format!(
+                "SASL mechanism '{}' requires a username",
+                mechanism.name()
+            ));
+        };
+        let password = SECRETS.get(Secret::SaslPassword).unwrap_or_default();
+        client_config
+            .set("sasl.username", username)
+            .set("sasl.password", password.as_str());
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: i64,
+}
+
+/// Requests access tokens for OAUTHBEARER using the client credentials grant
+pub struct OAuthClient {
+    token_endpoint: String,
+    client_id: String,
+    client_secret: SecretValue,
+    scope: Option<String>,
+    http: reqwest::Client,
+    runtime: Handle,
+}
+
+impl OAuthClient {
+    /// Creates a client using the current Tokio runtime to request tokens
+    pub fn new(
+        token_endpoint: &str,
+        client_id: &str,
+        client_secret: impl Into<SecretValue>,
+    ) -> Self {
+        Self {
+            token_endpoint: token_endpoint.to_string(),
+            client_id: client_id.to_string(),
+            client_secret: client_secret.into(),
+            scope: None,
+            http: reqwest::Client::new(),
+            runtime: Handle::current(),
+        }
+    }
+
+    pub fn with_scope(mut self, scope: Option<&str>) -> Self {
+        self.scope = scope.map(ToString::to_string);
+        self
+    }
+
+    async fn request_token(&self) -> Result<OAuthToken, String> {
+        let mut form = vec![("grant_type", "client_credentials")];
+        if let Some(scope) = &self.scope {
+            form.push(("scope", scope));
+        }
+
+        let client_secret = self.client_secret.get();
+        let response = self
+            .http
+            .post(&self.token_endpoint)
+            .basic_auth(&self.client_id, client_secret.as_deref())
+            .form(&form)
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(|err| format!("Cannot request token: {err}"))?
+            .json::<TokenResponse>()
+            .await
+            .map_err(|err| format!("Invalid token response: {err}"))?;
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|err| err.to_string())?;
+        Ok(OAuthToken {
+            token: response.access_token,
+            principal_name: self.client_id.clone(),
+            lifetime_ms: i64::try_from(now.as_millis()).unwrap_or(i64::MAX)
+                + response.expires_in * 1000,
+        })
+    }
+
+    /// Requests a token blocking the calling thread. The request runs on a separate thread as
+    /// librdkafka might call this from within the Tokio runtime.
+    pub fn token(&self) -> Result<OAuthToken, String> {
+        std::thread::scope(|scope| {
+            scope
+                .spawn(|| self.runtime.block_on(self.request_token()))
+                .join()
+                .map_err(|_| "Token request aborted".to_string())?
+        })
+    }
+}
+
+/// Kafka client context to track the producer queue and refresh OAUTHBEARER tokens
+#[derive(Clone, Default)]
+pub struct KafkaContext {
+    oauth: Option<Arc<OAuthClient>>,
+}
+
+impl KafkaContext {
+    pub fn with_oauth(oauth: OAuthClient) -> Self {
+        Self {
+            oauth: Some(Arc::new(oauth)),
+        }
+    }
+
+    pub fn from_config() -> Result<Self, String> {
+        if CONFIG.sasl_mechanism != Some(SaslMechanism::Oauthbearer) {
+            return Ok(Self::default());
+        }
+
+        let (Some(token_endpoint), Some(client_id)) = (
+            &CONFIG.sasl_oauth_token_endpoint,
+            &CONFIG.sasl_oauth_client_id,
+        ) else {
+            return Err(
+                "SASL mechanism 'OAUTHBEARER' requires token endpoint and client ID".into(),
+            );
+        };
+        // The client secret is looked up for each token request as it might be replaced
+        Ok(Self::with_oauth(
+            OAuthClient::new(
+                token_endpoint,
+                client_id,
+                SecretValue::Configured(Secret::SaslOauthClient),
+            )
+            .with_scope(CONFIG.sasl_oauth_scope.as_deref()),
+        ))
+    }
+}
+
+impl ClientContext for KafkaContext {
+    const ENABLE_REFRESH_OAUTH_TOKEN: bool = true;
+
+    fn stats(&self, statistics: Statistics) {
+        METRICS.observe_producer_queue(&statistics);
+    }
+
+    fn generate_oauth_token(
+        &self,
+        _oauthbearer_config: Option<&str>,
+    ) -> Result<OAuthToken, Box<dyn Error>> {
+        let Some(oauth) = &self.oauth else {
+            return Err("No OAuth token endpoint configured".into());
+        };
+        oauth.token().map_err(|err| {
+            log::error!("Cannot refresh Kafka OAUTHBEARER token: {err}");
+            err.into()
+        })
+    }
+}
+
+impl ConsumerContext for KafkaContext {}
+
+/// Property passed to librdkafka as `<key>=<value>`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KafkaProperty {
+    pub key: String,
+    pub value: String,
+}
+
+impl FromStr for KafkaProperty {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((key, value)) if !key.trim().is_empty() => Ok(Self {
+                key: key.trim().to_string(),
+                value: value.trim().to_string(),
+            }),
+            _ => Err(format!("Expected '<key>=<value>' but got '{s}'")),
+        }
+    }
+}
+
+/// Maps environment variables like `KAFKA_PROP_LINGER_MS` to properties like `linger.ms`
+pub fn env_properties(vars: impl Iterator<Item = (String, String)>) -> Vec<KafkaProperty> {
+    let mut properties = vars
+        .filter_map(|(name, value)| {
+            let key = name.strip_prefix(ENV_PREFIX)?;
+            Some(KafkaProperty {
+                key: key.to_lowercase().replace('_', "."),
+                value,
+            })
+        })
+        .collect::<Vec<_>>();
+    properties.sort_by(|a, b| a.key.cmp(&b.key));
+    properties
+}
+
+/// Properties of the config file
+pub fn file_properties(config_file: &ConfigFile) -> Vec<KafkaProperty> {
+    config_file
+        .kafka
+        .properties
+        .iter()
+        .map(|(key, value)| KafkaProperty {
+            key: key.clone(),
+            value: match value {
+                toml::Value::String(value) => value.clone(),
+                value => value.to_string(),
+            },
+        })
+        .collect()
+}
+
+/// Checks if librdkafka knows the property and accepts its value. Values are not part of
+/// error messages as they might be secrets.
+pub fn validate(property: &KafkaProperty) -> Result<(), String> {
+    ClientConfig::new()
+        .set(&property.key, &property.value)
+        .create_native_config()
+        .map(|_| ())
+        .map_err(|err| match err {
+            KafkaError::ClientConfig(_, description, _, _) => {
+                format!("Invalid Kafka property '{}': {description}", property.key)
+            }
+            _ => format!("Invalid Kafka property '{}'", property.key),
+        })
+}
+
+/// Merges properties of the config file, environment and command line, the latter taking
+/// precedence, and validates all of them
+pub fn properties(
+    config_file: &ConfigFile,
+    env: Vec<KafkaProperty>,
+    cli: &[KafkaProperty],
+) -> Result<Vec<KafkaProperty>, String> {
+    let properties = file_properties(config_file)
+        .into_iter()
+        .chain(env)
+        .chain(cli.iter().cloned())
+        .collect::<Vec<_>>();
+    for property in &properties {
+        validate(property)?;
+    }
+    Ok(properties)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::ConfigFile;
+    use crate::kafka::{KafkaProperty, OAuthClient, env_properties, properties, validate};
+    use axum::routing::post;
+    use axum::{Form, Json, Router};
+    use rstest::rstest;
+    use serde_json::json;
+    use std::collections::HashMap;
+    use std::str::FromStr;
+
+    fn property(key: &str, value: &str) -> KafkaProperty {
+        KafkaProperty {
+            key: key.to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    #[rstest]
+    #[case("acks=all", Ok(property("acks", "all")))]
+    #[case("sasl.jaas.config=a=b", Ok(property("sasl.jaas.config", "a=b")))]
+    #[case("acks", Err(()))]
+    #[case("=all", Err(()))]
+    fn should_parse_property(#[case] value: &str, #[case] expected: Result<KafkaProperty, ()>) {
+        assert_eq!(KafkaProperty::from_str(value).map_err(|_| ()), expected);
+    }
+
+    #[test]
+    fn should_map_env_properties() {
+        let vars = vec![
+            ("KAFKA_PROP_LINGER_MS".to_string(), "10".to_string()),
+            ("KAFKA_PROP_ACKS".to_string(), "all".to_string()),
+            ("KAFKA_TOPIC".to_string(), "test".to_string()),
+        ];
+
+        assert_eq!(
+            env_properties(vars.into_iter()),
+            vec![property("acks", "all"), property("linger.ms", "10")]
+        );
+    }
+
+    #[test]
+    fn should_validate_properties() {
+        assert_eq!(validate(&property("acks", "all")), Ok(()));
+        assert!(
+            validate(&property("unknown.property", "1"))
+                .is_err_and(|err| err.contains("'unknown.property'"))
+        );
+        assert!(validate(&property("linger.ms", "soon")).is_err());
+    }
+
+    #[test]
+    #[allow(clippy::expect_used)]
+    fn should_merge_properties_in_order() {
+        let config_file = ConfigFile::parse(
+            r#"
+            [kafka.properties]
+            "acks" = 1
+            "linger.ms" = 10
+            "#,
+        )
+        .expect("valid config");
+
+        let properties = properties(
+            &config_file,
+            vec![property("acks", "all")],
+            &[property("linger.ms", "20")],
+        );
+
+        assert_eq!(
+            properties,
+            Ok(vec![
+                property("acks", "1"),
+                property("linger.ms", "10"),
+                property("acks", "all"),
+                property("linger.ms", "20"),
+            ])
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[allow(clippy::expect_used)]
+    async fn should_request_oauth_token() {
+        let app = Router::new().route(
+            "/token",
+            post(|Form(form): Form<HashMap<String, String>>| async move {
+                assert_eq!(
+                    form.get("grant_type").map(String::as_str),
+                    Some("client_credentials")
+                );
+                assert_eq!(form.get("scope").map(String::as_str), Some("kafka"));
+                Json(json!({ "access_token": "token", "expires_in": 300 }))
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("listener bound");
+        let url = format!(
+            "http://{}/token",
+            listener.local_addr().expect("local address")
+        );
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let client = OAuthClient::new(&url, "gateway", "secret").with_scope(Some("kafka"));
+        let token = tokio::task::spawn_blocking(move || client.token())
+            .await
+            .expect("token requested")
+            .expect("token");
+
+        assert_eq!(token.token, "token");
+        assert_eq!(token.principal_name, "gateway");
+        assert!(token.lifetime_ms > 300_000);
+    }
+}
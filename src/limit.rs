@@ -0,0 +1,204 @@
+use axum::body::{Body, Bytes};
+use axum::http::Request;
+use axum::http::header::CONTENT_LENGTH;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::{BoxError, Extension};
+use futures_util::StreamExt;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::io;
+
+use crate::CONFIG;
+use crate::problem::{Problem, ProblemType};
+
+/// Default of the Kafka producer property `message.max.bytes`
+pub const KAFKA_MESSAGE_MAX_BYTES: usize = 1_000_000;
+
+pub type BodyLimitsExtension = Option<Extension<BodyLimits>>;
+
+/// Maximum sizes of request bodies in bytes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BodyLimits {
+    /// Request body containing a single MTB file
+    pub body: usize,
+    /// MTB file after decompressing the request body
+    pub decompressed: usize,
+    /// Request body containing multiple MTB files, not limited if `None`
+    pub bulk_body: Option<usize>,
+}
+
+impl BodyLimits {
+    /// Uses the configured limits, by default the maximum size of Kafka records
+    pub fn new(message_max_bytes: usize) -> Self {
+        Self {
+            body: CONFIG.max_body_size.unwrap_or(message_max_bytes),
+            decompressed: CONFIG.max_decompressed_size.unwrap_or(message_max_bytes),
+            bulk_body: CONFIG.max_bulk_body_size,
+        }
+    }
+
+    /// Options with limits exceeding the maximum size of Kafka records
+    pub fn exceeding(&self, message_max_bytes: usize) -> Vec<(&'static str, usize)> {
+        [
+            ("MAX_BODY_SIZE", self.body),
+            ("MAX_DECOMPRESSED_SIZE", self.decompressed),
+        ]
+        .into_iter()
+        .filter(|(_, limit)| *limit > message_max_bytes)
+        .collect()
+    }
+}
+
+impl Default for BodyLimits {
+    fn default() -> Self {
+        Self::new(KAFKA_MESSAGE_MAX_BYTES)
+    }
+}
+
+/// Error reading a request body exceeding the limit
+#[derive(Debug)]
+pub struct LimitExceeded(pub usize);
+
+impl LimitExceeded {
+    pub fn problem(&self) -> Problem {
+        Problem::new(ProblemType::PayloadTooLarge)
+            .with_detail(&self.to_string())
+            .with_limit(self.0)
+    }
+}
+
+impl Display for LimitExceeded {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The request body exceeds the limit of {} bytes", self.0)
+    }
+}
+
+impl Error for LimitExceeded {}
+
+/// Returns the exceeded limit if reading a request body failed because of it
+pub fn exceeded<'a>(err: &'a (dyn Error + 'static)) -> Option<&'a LimitExceeded> {
+    let mut current = Some(err);
+    while let Some(err) = current {
+        if let Some(exceeded) = err.downcast_ref::<LimitExceeded>() {
+            return Some(exceeded);
+        }
+        // Decompression wraps errors of the compressed body into IO errors
+        current = match err.downcast_ref::<io::Error>() {
+            Some(err) => err.get_ref().map(|err| err as &(dyn Error + 'static)),
+            None => err.source(),
+        };
+    }
+    None
+}
+
+/// Fails reading the body after more bytes than the limit
+pub fn limit_body(body: Body, limit: usize) -> Body {
+    let mut size = 0;
+    Body::from_stream(
+        body.into_data_stream()
+            .map(move |chunk| -> Result<Bytes, BoxError> {
+                let chunk = chunk?;
+                size += chunk.len();
+                if size > limit {
+                    return Err(LimitExceeded(limit).into());
+                }
+                Ok(chunk)
+            }),
+    )
+}
+
+async fn limit_request(limit: usize, request: Request<Body>, next: Next) -> Response {
+    let content_length = request
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok()?.parse::<usize>().ok());
+    if content_length.is_some_and(|content_length| content_length > limit) {
+        return LimitExceeded(limit).problem().into_response();
+    }
+    next.run(request.map(|body| limit_body(body, limit))).await
+}
+
+/// Limits provided as extension or the default limits
+pub fn limits(limits: BodyLimitsExtension) -> BodyLimits {
+    limits.map_or_else(BodyLimits::default, |Extension(limits)| limits)
+}
+
+/// Limits request bodies containing a single MTB file
+pub async fn limit_body_size(
+    body_limits: BodyLimitsExtension,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    limit_request(limits(body_limits).body, request, next).await
+}
+
+/// Limits request bodies after decompression
+pub async fn limit_decompressed_size(
+    body_limits: BodyLimitsExtension,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    limit_request(limits(body_limits).decompressed, request, next).await
+}
+
+/// Limits request bodies containing multiple MTB files
+pub async fn limit_bulk_body_size(
+    body_limits: BodyLimitsExtension,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    match limits(body_limits).bulk_body {
+        Some(limit) => limit_request(limit, request, next).await,
+        None => next.run(request).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::limit::{BodyLimits, LimitExceeded, exceeded, limit_body};
+    use axum::body::Body;
+    use http_body_util::BodyExt;
+
+    #[tokio::test]
+    async fn should_fail_reading_body_exceeding_limit() {
+        let result = limit_body(Body::from("0123456789"), 9).collect().await;
+
+        let err = result.err().map(axum::Error::into_inner);
+        assert_eq!(
+            err.as_deref()
+                .and_then(|err| exceeded(err))
+                .map(|err| err.0),
+            Some(9)
+        );
+        assert!(
+            limit_body(Body::from("0123456789"), 10)
+                .collect()
+                .await
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn should_find_limit_wrapped_in_io_error() {
+        let err = std::io::Error::other(LimitExceeded(42));
+
+        assert_eq!(exceeded(&err).map(|err| err.0), Some(42));
+        assert!(exceeded(&std::io::Error::other("other")).is_none());
+    }
+
+    #[test]
+    fn should_return_limits_exceeding_kafka_limit() {
+        let limits = BodyLimits {
+            body: 1_000_000,
+            decompressed: 10_000_000,
+            bulk_body: None,
+        };
+
+        assert_eq!(
+            limits.exceeding(1_000_000),
+            vec![("MAX_DECOMPRESSED_SIZE", 10_000_000)]
+        );
+        assert!(limits.exceeding(10_000_000).is_empty());
+    }
+}
@@ -2,23 +2,69 @@ use axum::body::Body;
 use axum::http::StatusCode;
 use axum::http::header::WWW_AUTHENTICATE;
 use axum::response::{IntoResponse, Response};
+use axum::{Extension, Json};
 use rdkafka::ClientConfig;
-use rdkafka::producer::FutureProducer;
+use rdkafka::consumer::{Consumer, StreamConsumer};
+use rdkafka::producer::{FutureProducer, Producer};
 use serde::{Deserialize, Serialize};
+use std::sync::atomic::AtomicBool;
 use std::sync::{Arc, LazyLock};
+use std::time::Duration;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
 
-#[cfg(not(test))]
-use clap::Parser;
-
-use crate::AppResponse::{Accepted, Unauthorized, UnsupportedContentType};
-use crate::auth::is_valid_brypt_hash;
-use crate::cli::Cli;
-use crate::sender::DefaultMtbFileSender;
+use crate::AppResponse::{
+    Accepted, Delivered, Forbidden, InternalServerError, NotFound, Unauthorized, Unchanged,
+    UnsupportedContentType,
+};
+use crate::cli::{Cli, Command, ConfigCommand};
+use crate::config::ConfigFile;
+use crate::health::ProducerProbe;
+use crate::idempotency::IdempotencyStore;
+use crate::kafka::KafkaContext;
+use crate::limit::BodyLimits;
+use crate::oidc::BearerAuth;
+use crate::outbox::{FsyncPolicy, Outbox, OutboxMtbFileSender};
+use crate::problem::{Problem, ProblemType};
+use crate::pseudonym::PseudonymizingMtbFileSender;
+use crate::request_id::RequestIdStore;
+use crate::routing::TopicRouting;
+use crate::secrets::{SECRETS, Secret};
+use crate::sender::{
+    DefaultMtbFileSender, DynMtbFileSender, SendReport, TRANSACTION_TIMEOUT,
+    TransactionalMtbFileSender,
+};
+use crate::status::RequestStatusStore;
+use crate::tls::ReloadingCertResolver;
+use crate::unchanged::{ContentHashStore, UnchangedSkippingMtbFileSender};
 
 mod auth;
+mod bulk;
 mod cli;
+mod config;
+mod expiring;
+mod extract;
+mod health;
+mod idempotency;
+mod kafka;
+mod limit;
+mod metadata;
+mod metrics;
+mod oidc;
+mod outbox;
+mod problem;
+mod pseudonym;
+mod request_id;
 mod routes;
+mod routing;
+mod secrets;
 mod sender;
+mod status;
+mod telemetry;
+mod tls;
+mod unchanged;
+mod validation;
 
 #[derive(Serialize, Deserialize)]
 struct RecordKey {
@@ -28,8 +74,12 @@ struct RecordKey {
 
 enum AppResponse<'a> {
     Accepted(&'a str),
+    Delivered(&'a SendReport),
+    Unchanged(&'a str),
     Unauthorized,
-    InternalServerError,
+    Forbidden,
+    NotFound,
+    InternalServerError(&'a str),
     UnsupportedContentType,
 }
 
@@ -37,63 +87,111 @@ enum AppResponse<'a> {
 impl IntoResponse for AppResponse<'_> {
     fn into_response(self) -> Response {
         match self {
-            UnsupportedContentType => (
-                StatusCode::UNSUPPORTED_MEDIA_TYPE,
-                "This application accepts DNPM data model version 2.1 with content type 'application/json'"
-            ).into_response(),
-            _ => match self {
-                Accepted(request_id) => Response::builder()
-                    .status(StatusCode::ACCEPTED)
-                    .header("X-Request-Id", request_id),
-                Unauthorized => Response::builder()
-                    .status(StatusCode::UNAUTHORIZED)
-                    .header(WWW_AUTHENTICATE, "Basic realm=\"DNPM Kafka Rest Proxy Realm\""),
-                _ => Response::builder().status(StatusCode::INTERNAL_SERVER_ERROR),
-            }
-                .body(Body::empty()).expect("response built"),
+            Accepted(request_id) => Response::builder()
+                .status(StatusCode::ACCEPTED)
+                .header("X-Request-Id", request_id)
+                .body(Body::empty())
+                .expect("response built"),
+            Delivered(report) => (
+                StatusCode::ACCEPTED,
+                [
+                    ("x-request-id", report.request_id.as_str()),
+                    ("preference-applied", "wait"),
+                ],
+                Json(report),
+            )
+                .into_response(),
+            Unchanged(request_id) => Response::builder()
+                .status(StatusCode::OK)
+                .header("X-Request-Id", request_id)
+                .body(Body::empty())
+                .expect("response built"),
+            Unauthorized => (
+                [(
+                    WWW_AUTHENTICATE,
+                    "Basic realm=\"DNPM Kafka Rest Proxy Realm\"",
+                )],
+                Problem::new(ProblemType::Unauthorized),
+            )
+                .into_response(),
+            Forbidden => Problem::new(ProblemType::Forbidden)
+                .with_detail("The user is not allowed to use this request method")
+                .into_response(),
+            NotFound => Problem::new(ProblemType::NotFound).into_response(),
+            InternalServerError(request_id) => Problem::new(ProblemType::DeliveryFailed)
+                .with_detail("The MTB file could not be sent to Kafka")
+                .with_request_id(request_id)
+                .into_response(),
+            UnsupportedContentType => Problem::new(ProblemType::UnsupportedContentType)
+                .with_detail("This application accepts DNPM data model version 2.1 with content type 'application/json'")
+                .into_response(),
         }
     }
 }
 
 #[cfg(not(test))]
-static CONFIG: LazyLock<Cli> = LazyLock::new(Cli::parse);
+static CONFIG: LazyLock<Cli> = LazyLock::new(cli::parse);
 
 #[tokio::main]
 async fn main() -> Result<(), ()> {
-    #[cfg(debug_assertions)]
+    if let Some(Command::Config {
+        command: ConfigCommand::Check,
+    }) = CONFIG.command
     {
-        tracing_subscriber::fmt()
-            .with_max_level(tracing::Level::DEBUG)
-            .init();
+        match cli::check(std::env::args_os()) {
+            Ok(config) => print!("{config}"),
+            Err(err) => err.exit(),
+        }
+        return Ok(());
     }
 
+    let tracer_provider = match telemetry::tracer_provider() {
+        Ok(tracer_provider) => tracer_provider,
+        Err(err) => {
+            eprintln!("Error starting application: {err}");
+            return Err(());
+        }
+    };
+
+    #[cfg(debug_assertions)]
+    let max_level = LevelFilter::DEBUG;
     #[cfg(not(debug_assertions))]
-    {
-        tracing_subscriber::fmt()
-            .with_max_level(tracing::Level::INFO)
-            .init();
-    }
+    let max_level = LevelFilter::INFO;
 
-    if !is_valid_brypt_hash(&CONFIG.token) {
-        log::error!("Error starting application: given token is not a valid BCrypt token");
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer())
+        .with(telemetry::layer(&tracer_provider))
+        .with(max_level)
+        .init();
+
+    if let Err(err) = SECRETS.reload() {
+        log::error!("Error starting application: {err}");
         return Err(());
     }
+    match auth::USERS.reload() {
+        Ok(len) => log::info!("Using {len} user(s) for authentication"),
+        Err(err) => {
+            log::error!("Error starting application: {err}");
+            return Err(());
+        }
+    }
+    #[cfg(unix)]
+    tokio::spawn(auth::reload_on_hangup());
 
     if let Err(err_msg) = start_service().await {
         log::error!("Error starting service: {err_msg}");
     }
 
+    // Exports pending spans
+    let _ = tracer_provider.shutdown();
     Ok(())
 }
 
-async fn start_service() -> Result<(), String> {
+fn client_config() -> Result<ClientConfig, String> {
     let mut client_config = ClientConfig::new();
+    client_config.set("bootstrap.servers", &CONFIG.bootstrap_server);
 
-    client_config
-        .set("bootstrap.servers", &CONFIG.bootstrap_server)
-        .set("message.timeout.ms", "5000");
-
-    let producer = if CONFIG.ssl_cert_file.is_some() || CONFIG.ssl_key_file.is_some() {
+    if CONFIG.ssl_cert_file.is_some() || CONFIG.ssl_key_file.is_some() {
         // Use SSL
         client_config
             .set("security.protocol", "ssl")
@@ -109,22 +207,267 @@ async fn start_service() -> Result<(), String> {
                 "ssl.key.location",
                 CONFIG.ssl_key_file.clone().unwrap_or_default(),
             );
-        if let Some(ssl_key_password) = &CONFIG.ssl_key_password {
-            client_config.set("ssl.key.password", ssl_key_password);
+        if let Some(ssl_key_password) = SECRETS.get(Secret::SslKeyPassword) {
+            client_config.set("ssl.key.password", ssl_key_password.as_str());
         }
-        client_config.create::<FutureProducer>().map_err(|err| err.to_string())?
+    }
+
+    kafka::configure_sasl(&mut client_config)?;
+    Ok(client_config)
+}
+
+fn open_outbox() -> Result<Option<Arc<Outbox>>, String> {
+    let Some(outbox_dir) = &CONFIG.outbox_dir else {
+        return Ok(None);
+    };
+
+    let outbox = Outbox::open(outbox_dir, CONFIG.outbox_max_size, CONFIG.outbox_fsync)
+        .map_err(|err| format!("Cannot open outbox in '{outbox_dir}': {err}"))?;
+    let outbox = Arc::new(outbox);
+    log::info!(
+        "Using outbox in '{outbox_dir}' with {} bytes pending",
+        outbox.size()
+    );
+
+    if CONFIG.outbox_fsync == FsyncPolicy::Interval {
+        tokio::spawn(outbox::sync_periodically(Arc::clone(&outbox)));
+    }
+    Ok(Some(outbox))
+}
+
+/// Sends MTB files using the outbox or, if a transactional ID is configured, in transactions.
+/// Sets `failed` if the transactional producer failed.
+async fn kafka_sender(
+    producer: FutureProducer<KafkaContext>,
+    failed: Arc<AtomicBool>,
+    content_hashes: Option<Arc<ContentHashStore>>,
+) -> Result<(DynMtbFileSender, Option<Arc<Outbox>>), String> {
+    let sender = DefaultMtbFileSender::new(&CONFIG.topic, producer.clone());
+
+    if let Some(transactional_id) = &CONFIG.transactional_id {
+        if CONFIG.outbox_dir.is_some() {
+            return Err("Transactions cannot be used with an outbox".to_string());
+        }
+        // Fences previous producers using the same transactional ID
+        tokio::task::spawn_blocking(move || producer.init_transactions(TRANSACTION_TIMEOUT))
+            .await
+            .map_err(|err| err.to_string())?
+            .map_err(|err| {
+                format!("Cannot initialize transactions using ID '{transactional_id}': {err}")
+            })?;
+        log::info!("Sending MTB files in transactions using ID '{transactional_id}'");
+        return Ok((
+            Arc::new(TransactionalMtbFileSender::new(
+                sender,
+                CONFIG.max_transaction_size,
+                failed,
+            )),
+            None,
+        ));
+    }
+
+    Ok(match open_outbox()? {
+        Some(outbox) => {
+            tokio::spawn(outbox::forward(Arc::clone(&outbox), sender, content_hashes));
+            let sender = Arc::new(OutboxMtbFileSender::new(Arc::clone(&outbox)));
+            (sender, Some(outbox))
+        }
+        None => (Arc::new(sender), None),
+    })
+}
+
+fn open_content_hash_store() -> Result<Option<Arc<ContentHashStore>>, String> {
+    let Some(file) = &CONFIG.unchanged_store_file else {
+        return Ok(None);
+    };
+
+    let store = ContentHashStore::open(file)
+        .map_err(|err| format!("Cannot open unchanged store file '{file}': {err}"))?;
+    log::info!("Skipping unchanged MTB files using '{file}'");
+    Ok(Some(Arc::new(store)))
+}
+
+fn skip_unchanged(
+    sender: DynMtbFileSender,
+    content_hashes: Option<Arc<ContentHashStore>>,
+) -> DynMtbFileSender {
+    match content_hashes {
+        Some(store) => Arc::new(UnchangedSkippingMtbFileSender::new(
+            store,
+            sender,
+            &CONFIG.topic,
+        )),
+        None => sender,
+    }
+}
+
+fn track_request_status(context: KafkaContext) -> Result<Option<Arc<RequestStatusStore>>, String> {
+    let Some(response_topic) = &CONFIG.response_topic else {
+        return Ok(None);
+    };
+
+    let request_status = match &CONFIG.request_status_file {
+        Some(file) => RequestStatusStore::open(file)
+            .map_err(|err| format!("Cannot open request status file '{file}': {err}"))?,
+        None => RequestStatusStore::new(),
+    };
+    let request_status = Arc::new(request_status);
+
+    let consumer = client_config()?
+        .set("group.id", &CONFIG.response_group_id)
+        .set("enable.auto.commit", "true")
+        .set("auto.offset.reset", "earliest")
+        .create_with_context::<_, StreamConsumer<KafkaContext>>(context)
+        .map_err(|err| err.to_string())?;
+    consumer
+        .subscribe(&[response_topic])
+        .map_err(|err| err.to_string())?;
+    log::info!("Tracking request status using response topic '{response_topic}'");
+
+    tokio::spawn(status::consume(consumer, Arc::clone(&request_status)));
+    Ok(Some(request_status))
+}
+
+fn producer_config(config_file: &ConfigFile) -> Result<ClientConfig, String> {
+    let mut producer_config = client_config()?;
+    producer_config
+        .set("message.timeout.ms", "5000")
+        .set("statistics.interval.ms", "5000");
+    if let Some(transactional_id) = &CONFIG.transactional_id {
+        producer_config.set("transactional.id", transactional_id);
+    }
+    for property in kafka::properties(
+        config_file,
+        kafka::env_properties(std::env::vars()),
+        &CONFIG.kafka_properties,
+    )? {
+        log::info!("Using Kafka property '{}'", property.key);
+        producer_config.set(property.key, property.value);
+    }
+    Ok(producer_config)
+}
+
+/// Limits request bodies to the size of records accepted by Kafka unless configured otherwise
+fn body_limits(producer_config: &ClientConfig) -> BodyLimits {
+    let message_max_bytes = producer_config
+        .get("message.max.bytes")
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(limit::KAFKA_MESSAGE_MAX_BYTES);
+    let body_limits = BodyLimits::new(message_max_bytes);
+    for (option, limit) in body_limits.exceeding(message_max_bytes) {
+        log::warn!(
+            "{option} of {limit} bytes exceeds Kafka property 'message.max.bytes' of {message_max_bytes} bytes. Larger MTB files will not be accepted by Kafka"
+        );
+    }
+    body_limits
+}
+
+async fn start_service() -> Result<(), String> {
+    let config_file = match &CONFIG.config {
+        Some(file) => ConfigFile::read(file)?,
+        None => ConfigFile::default(),
+    };
+
+    let kafka_context = KafkaContext::from_config()?;
+    let producer_config = producer_config(&config_file)?;
+    let body_limits = body_limits(&producer_config);
+
+    let producer = producer_config
+        .create_with_context::<_, FutureProducer<KafkaContext>>(kafka_context.clone())
+        .map_err(|err| err.to_string())?;
+
+    let probe = Arc::new(ProducerProbe::new(producer.clone()));
+    let content_hashes = open_content_hash_store()?;
+    let (sender, outbox) = kafka_sender(producer, probe.failed(), content_hashes.clone()).await?;
+
+    // Pseudonymize before storing MTB files in the outbox
+    let sender: DynMtbFileSender = if let Some(pseudonymizer) = pseudonym::from_config()? {
+        log::info!(
+            "Pseudonymizing patient IDs using {:?}",
+            CONFIG.pseudonymizer
+        );
+        Arc::new(PseudonymizingMtbFileSender::new(pseudonymizer, sender))
     } else {
-        // Plain
-        client_config.create::<FutureProducer>().map_err(|err| err.to_string())?
+        sender
     };
 
-    let sender = Arc::new(DefaultMtbFileSender::new(&CONFIG.topic, producer));
+    // Compare MTB files before pseudonymization to use the original patient IDs
+    let sender = skip_unchanged(sender, content_hashes);
+
+    let routing = TopicRouting::new(&CONFIG.topic, config_file.routing.rules)?;
+    if !routing.is_empty() {
+        log::info!("Routing MTB files using {} rule(s)", routing.len());
+    }
+    let mut app = routes::routes(sender)
+        .layer(Extension(Arc::new(routing)))
+        .layer(Extension(body_limits));
+
+    if let Some(bearer_auth) = BearerAuth::from_config()? {
+        log::info!(
+            "Accepting bearer tokens issued by '{}'",
+            bearer_auth.issuer()
+        );
+        app = app.layer(Extension(Arc::new(bearer_auth)));
+    }
+
+    if CONFIG.idempotency_ttl > 0 {
+        app = app.layer(Extension(Arc::new(IdempotencyStore::new(
+            Duration::from_secs(CONFIG.idempotency_ttl),
+            CONFIG.idempotency_content_hash,
+        ))));
+    }
+
+    if CONFIG.request_id_ttl > 0 {
+        app = app.layer(Extension(Arc::new(RequestIdStore::new(
+            Duration::from_secs(CONFIG.request_id_ttl),
+        ))));
+    }
+
+    if let Some(request_status) = track_request_status(kafka_context)? {
+        app = app.layer(Extension(request_status));
+    }
+
+    app = app.merge(health::routes(probe, outbox));
+
+    if let Some(metrics_listen) = &CONFIG.metrics_listen {
+        let listener = tokio::net::TcpListener::bind(metrics_listen)
+            .await
+            .map_err(|err| format!("Cannot listening on '{metrics_listen}': {err}"))?;
+        log::info!("Providing metrics on '{metrics_listen}'");
+        tokio::spawn(async move {
+            if let Err(err) = axum::serve(listener, metrics::routes()).await {
+                log::error!("Cannot provide metrics on '{metrics_listen}': {err}");
+            }
+        });
+    } else {
+        app = app.merge(metrics::routes());
+    }
+
+    let tls_config = match (&CONFIG.tls_cert_file, &CONFIG.tls_key_file) {
+        (Some(cert_file), Some(key_file)) => {
+            let resolver = Arc::new(ReloadingCertResolver::new(cert_file, key_file)?);
+            tokio::spawn(tls::reload_periodically(Arc::clone(&resolver)));
+            Some(tls::server_config(
+                resolver,
+                CONFIG.tls_client_ca_file.as_deref(),
+            )?)
+        }
+        _ => None,
+    };
 
     match tokio::net::TcpListener::bind(&CONFIG.listen).await {
         Ok(listener) => {
-            log::info!("Starting application listening on '{}'", CONFIG.listen);
-            if let Err(err) = axum::serve(listener, routes::routes(sender)).await {
-                return Err(err.to_string());
+            if let Some(tls_config) = tls_config {
+                log::info!(
+                    "Starting application listening on '{}' using HTTPS",
+                    CONFIG.listen
+                );
+                tls::serve(listener, app, tls_config).await;
+            } else {
+                log::info!("Starting application listening on '{}'", CONFIG.listen);
+                if let Err(err) = axum::serve(listener, app).await {
+                    return Err(err.to_string());
+                }
             }
         }
         Err(err) => return Err(format!("Cannot listening on '{}': {}", CONFIG.listen, err)),
@@ -139,12 +482,70 @@ static CONFIG: LazyLock<Cli> = LazyLock::new(|| Cli {
     bootstrap_server: "localhost:9094".to_string(),
     topic: "test-topic".to_string(),
     // Basic dG9rZW46dmVyeS1zZWNyZXQ=
-    token: "$2y$05$LIIFF4Rbi3iRVA4UIqxzPeTJ0NOn/cV2hDnSKFftAMzbEZRa42xSG".to_string(),
+    token: Some("$2y$05$LIIFF4Rbi3iRVA4UIqxzPeTJ0NOn/cV2hDnSKFftAMzbEZRa42xSG".to_string()),
+    token_file: None,
+    users_file: None,
+    oidc_jwks_file: None,
+    oidc_jwks_url: None,
+    oidc_jwks_cache_ttl: 300,
+    oidc_issuer: None,
+    oidc_audience: None,
+    oidc_post_scope: None,
+    oidc_delete_scope: None,
+    command: None,
+    config: None,
     listen: "0.0.0.0:3000".to_string(),
+    metrics_listen: None,
+    otlp_endpoint: None,
+    otlp_service_name: "mv64e-rest-to-kafka-gateway".to_string(),
+    tls_cert_file: None,
+    tls_key_file: None,
+    tls_client_ca_file: None,
+    tls_client_allow: vec![],
+    tls_client_auth: tls::ClientCertAuth::Complement,
+    max_body_size: None,
+    max_bulk_body_size: None,
+    max_decompressed_size: None,
     ssl_ca_file: None,
     ssl_cert_file: None,
     ssl_key_file: None,
     ssl_key_password: None,
+    ssl_key_password_file: None,
+    sasl_mechanism: None,
+    sasl_plaintext: false,
+    sasl_username: None,
+    sasl_password: None,
+    sasl_password_file: None,
+    sasl_oauth_token_endpoint: None,
+    sasl_oauth_client_id: None,
+    sasl_oauth_client_secret: None,
+    sasl_oauth_client_secret_file: None,
+    sasl_oauth_scope: None,
+    kafka_properties: vec![],
+    outbox_dir: None,
+    outbox_max_size: 1_073_741_824,
+    outbox_fsync: FsyncPolicy::Always,
+    response_topic: None,
+    response_group_id: "mv64e-rest-to-kafka-gateway".to_string(),
+    transactional_id: None,
+    max_transaction_size: 1000,
+    request_status_file: None,
+    wait_for_delivery: false,
+    idempotency_ttl: 86400,
+    idempotency_content_hash: false,
+    request_id_ttl: 86400,
+    source_headers: vec![],
+    unchanged_store_file: None,
+    validation_rules: vec![],
+    pseudonymizer: None,
+    pseudonym_hmac_key: None,
+    pseudonym_hmac_key_file: None,
+    pseudonym_mapping_file: None,
+    gpas_url: None,
+    gpas_domain: None,
+    gpas_username: None,
+    gpas_password: None,
+    gpas_password_file: None,
 });
 
 #[cfg(test)]
@@ -165,7 +566,7 @@ mod tests {
 
     #[test]
     fn should_return_error_response() {
-        let response = InternalServerError.into_response();
+        let response = InternalServerError(&Uuid::new_v4().to_string()).into_response();
         assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
         assert!(!response.headers().contains_key("x-request-id"));
     }
@@ -0,0 +1,73 @@
+use axum::http::{HeaderMap, HeaderName};
+use std::str::FromStr;
+
+/// Prefix of HTTP headers containing metadata of the source, e.g. the source system or export job
+const SOURCE_HEADER_PREFIX: &str = "x-dnpm-";
+
+/// Allowed HTTP header with source metadata, copied into a Kafka header of the same name
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SourceHeader(pub HeaderName);
+
+impl FromStr for SourceHeader {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name = HeaderName::from_str(s.trim())
+            .map_err(|_| format!("Expected the name of an HTTP header but got '{s}'"))?;
+        if !name.as_str().starts_with(SOURCE_HEADER_PREFIX) || name.as_str() == SOURCE_HEADER_PREFIX
+        {
+            return Err(format!(
+                "Expected an HTTP header starting with 'X-Dnpm-' but got '{s}'"
+            ));
+        }
+        Ok(Self(name))
+    }
+}
+
+/// Names and values of the allowed source headers given in the request
+pub fn source_headers(headers: &HeaderMap, allowed: &[SourceHeader]) -> Vec<(String, String)> {
+    allowed
+        .iter()
+        .filter_map(|SourceHeader(name)| {
+            let value = headers.get(name)?.to_str().ok()?;
+            Some((name.to_string(), value.to_string()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::metadata::{SourceHeader, source_headers};
+    use axum::http::{HeaderMap, HeaderValue};
+    use std::str::FromStr;
+
+    #[test]
+    fn should_only_allow_dnpm_headers() {
+        assert!(SourceHeader::from_str("X-Dnpm-Source-System").is_ok());
+        assert!(SourceHeader::from_str("X-Dnpm-").is_err());
+        assert!(SourceHeader::from_str("Authorization").is_err());
+        assert!(SourceHeader::from_str("X-Dnpm Export").is_err());
+    }
+
+    #[test]
+    #[allow(clippy::unwrap_used)]
+    fn should_return_allowed_source_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-dnpm-source-system", HeaderValue::from_static("onkostar"));
+        headers.insert("x-dnpm-export-job", HeaderValue::from_static("2024-03-09"));
+        headers.insert("x-dnpm-other", HeaderValue::from_static("ignored"));
+
+        let allowed = [
+            SourceHeader::from_str("X-Dnpm-Export-Job").unwrap(),
+            SourceHeader::from_str("X-Dnpm-Source-System").unwrap(),
+            SourceHeader::from_str("X-Dnpm-Missing").unwrap(),
+        ];
+        assert_eq!(
+            source_headers(&headers, &allowed),
+            vec![
+                ("x-dnpm-export-job".to_string(), "2024-03-09".to_string()),
+                ("x-dnpm-source-system".to_string(), "onkostar".to_string()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,245 @@
+use axum::Router;
+use axum::body::Body;
+use axum::extract::MatchedPath;
+use axum::http::header::CONTENT_LENGTH;
+use axum::http::{Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder, exponential_buckets,
+};
+use rdkafka::Statistics;
+use std::sync::LazyLock;
+use std::time::Duration;
+
+use crate::problem::ProblemType;
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    request_body_size: HistogramVec,
+    delivery_latency: Histogram,
+    producer_queue_messages: IntGauge,
+    producer_queue_bytes: IntGauge,
+    auth_failures: IntCounterVec,
+}
+
+impl Metrics {
+    #[allow(clippy::expect_used)]
+    fn new() -> Self {
+        let registry =
+            Registry::new_custom(Some("gateway".to_string()), None).expect("valid registry");
+
+        let requests = IntCounterVec::new(
+            Opts::new(
+                "requests_total",
+                "Number of requests by route, outcome and reason",
+            ),
+            &["route", "outcome", "reason"],
+        )
+        .expect("valid metric");
+        let request_body_size = HistogramVec::new(
+            HistogramOpts::new("request_body_bytes", "Size of request bodies in bytes")
+                .buckets(exponential_buckets(1024.0, 4.0, 8).expect("valid buckets")),
+            &["route"],
+        )
+        .expect("valid metric");
+        let delivery_latency = Histogram::with_opts(HistogramOpts::new(
+            "kafka_delivery_seconds",
+            "Time until delivery to Kafka was acknowledged",
+        ))
+        .expect("valid metric");
+        let producer_queue_messages = IntGauge::new(
+            "kafka_producer_queue_messages",
+            "Number of messages in the Kafka producer queue",
+        )
+        .expect("valid metric");
+        let producer_queue_bytes = IntGauge::new(
+            "kafka_producer_queue_bytes",
+            "Size of messages in the Kafka producer queue in bytes",
+        )
+        .expect("valid metric");
+        let auth_failures = IntCounterVec::new(
+            Opts::new("auth_failures_total", "Number of failed authentications"),
+            &["reason"],
+        )
+        .expect("valid metric");
+
+        registry
+            .register(Box::new(requests.clone()))
+            .and_then(|()| registry.register(Box::new(request_body_size.clone())))
+            .and_then(|()| registry.register(Box::new(delivery_latency.clone())))
+            .and_then(|()| registry.register(Box::new(producer_queue_messages.clone())))
+            .and_then(|()| registry.register(Box::new(producer_queue_bytes.clone())))
+            .and_then(|()| registry.register(Box::new(auth_failures.clone())))
+            .expect("metrics registered");
+
+        Self {
+            registry,
+            requests,
+            request_body_size,
+            delivery_latency,
+            producer_queue_messages,
+            producer_queue_bytes,
+            auth_failures,
+        }
+    }
+
+    pub fn observe_delivery(&self, duration: Duration) {
+        self.delivery_latency.observe(duration.as_secs_f64());
+    }
+
+    /// Tracks the producer queue using Kafka client statistics
+    pub fn observe_producer_queue(&self, statistics: &Statistics) {
+        self.producer_queue_messages
+            .set(i64::try_from(statistics.msg_cnt).unwrap_or(i64::MAX));
+        self.producer_queue_bytes
+            .set(i64::try_from(statistics.msg_size).unwrap_or(i64::MAX));
+    }
+
+    pub fn auth_failed(&self, problem_type: ProblemType) {
+        self.auth_failures
+            .with_label_values(&[problem_type.name()])
+            .inc();
+    }
+
+    /// Returns all metrics in Prometheus text format
+    pub fn render(&self) -> String {
+        let mut buffer = vec![];
+        if let Err(err) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            log::error!("Cannot encode metrics: {err}");
+        }
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
+
+/// Counts requests by route and outcome. The reason of rejected or failed requests is the
+/// problem type of the response.
+pub async fn track_requests(request: Request<Body>, next: Next) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("unmatched", MatchedPath::as_str)
+        .to_string();
+    if let Some(size) = request
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u32>().ok())
+    {
+        METRICS
+            .request_body_size
+            .with_label_values(&[&route])
+            .observe(f64::from(size));
+    }
+
+    let response = next.run(request).await;
+
+    let outcome = if response.status().is_server_error() {
+        "failed"
+    } else if response.status().is_client_error() {
+        "rejected"
+    } else {
+        "accepted"
+    };
+    let reason = response
+        .extensions()
+        .get::<ProblemType>()
+        .map_or("none", |problem_type| problem_type.name());
+    METRICS
+        .requests
+        .with_label_values(&[&route, outcome, reason])
+        .inc();
+
+    response
+}
+
+async fn handle_metrics() -> Response {
+    (
+        StatusCode::OK,
+        [("content-type", "text/plain; version=0.0.4")],
+        METRICS.render(),
+    )
+        .into_response()
+}
+
+pub fn routes() -> Router {
+    Router::new().route("/metrics", get(handle_metrics))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::metrics::{METRICS, routes, track_requests};
+    use crate::problem::{Problem, ProblemType};
+    use axum::Router;
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use axum::middleware::from_fn;
+    use axum::response::IntoResponse;
+    use axum::routing::post;
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    #[allow(clippy::expect_used)]
+    async fn should_count_requests_by_route_and_reason() {
+        let app = Router::new()
+            .route(
+                "/metrics-test/{id}",
+                post(|| async { Problem::new(ProblemType::InvalidJson).into_response() }),
+            )
+            .layer(from_fn(track_requests));
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/metrics-test/42")
+                    .header("content-length", "2")
+                    .body(Body::from("{}"))
+                    .expect("request built"),
+            )
+            .await
+            .expect("response");
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let metrics = METRICS.render();
+        assert!(metrics.contains(
+            "gateway_requests_total{outcome=\"rejected\",reason=\"invalid-json\",route=\"/metrics-test/{id}\"} 1"
+        ));
+        assert!(
+            metrics.contains("gateway_request_body_bytes_count{route=\"/metrics-test/{id}\"} 1")
+        );
+    }
+
+    #[tokio::test]
+    #[allow(clippy::expect_used)]
+    async fn should_expose_metrics() {
+        METRICS.auth_failed(ProblemType::Unauthorized);
+
+        let response = routes()
+            .oneshot(
+                Request::builder()
+                    .uri("/metrics")
+                    .body(Body::empty())
+                    .expect("request built"),
+            )
+            .await
+            .expect("response");
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response
+            .into_body()
+            .collect()
+            .await
+            .expect("body collected")
+            .to_bytes();
+        let metrics = String::from_utf8_lossy(&body);
+        assert!(metrics.contains("gateway_auth_failures_total{reason=\"unauthorized\"}"));
+        assert!(metrics.contains("# TYPE gateway_kafka_delivery_seconds histogram"));
+    }
+}
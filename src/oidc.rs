@@ -0,0 +1,469 @@
+use axum::http::Method;
+use jsonwebtoken::jwk::{Jwk, JwkSet};
+use jsonwebtoken::{Algorithm, DecodingKey, Header, Validation, decode, decode_header};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::CONFIG;
+use crate::auth::AuthError;
+
+/// Minimum time between two requests of the JWKS URL if a token uses an unknown key
+const MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Timeouts requesting the JWKS URL, so an unresponsive identity provider does not block requests
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Only asymmetric algorithms are accepted to validate tokens using public keys
+const ALGORITHMS: [Algorithm; 9] = [
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::PS384,
+    Algorithm::PS512,
+    Algorithm::ES256,
+    Algorithm::ES384,
+    Algorithm::EdDSA,
+];
+
+/// Source of the keys used to sign bearer tokens
+pub enum Jwks {
+    Static(JwkSet),
+    Remote {
+        url: String,
+        ttl: Duration,
+        client: reqwest::Client,
+        cache: Mutex<Option<(Instant, Arc<JwkSet>)>>,
+    },
+}
+
+impl Jwks {
+    pub fn read(path: &str) -> Result<Self, String> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|err| format!("Cannot read JWKS file '{path}': {err}"))?;
+        let jwks = serde_json::from_str(&content)
+            .map_err(|err| format!("Invalid JWKS file '{path}': {err}"))?;
+        Ok(Jwks::Static(jwks))
+    }
+
+    pub fn remote(url: &str, ttl: Duration) -> Self {
+        Jwks::Remote {
+            url: url.to_string(),
+            ttl,
+            client: reqwest::Client::builder()
+                .connect_timeout(CONNECT_TIMEOUT)
+                .timeout(REQUEST_TIMEOUT)
+                .build()
+                .unwrap_or_default(),
+            cache: Mutex::new(None),
+        }
+    }
+
+    async fn find(&self, kid: Option<&str>) -> Result<Jwk, String> {
+        match self {
+            Jwks::Static(jwks) => find_key(jwks, kid),
+            Jwks::Remote {
+                url,
+                ttl,
+                client,
+                cache,
+            } => {
+                let cached = cache.lock().ok().and_then(|cache| cache.clone());
+                if let Some((fetched_at, jwks)) = &cached
+                    && fetched_at.elapsed() < *ttl
+                {
+                    match find_key(jwks, kid) {
+                        Ok(jwk) => return Ok(jwk),
+                        // Keys might have been rotated
+                        Err(err) if fetched_at.elapsed() < MIN_REFRESH_INTERVAL => return Err(err),
+                        Err(_) => {}
+                    }
+                }
+
+                match fetch(client, url).await {
+                    Ok(jwks) => {
+                        let jwks = Arc::new(jwks);
+                        if let Ok(mut cache) = cache.lock() {
+                            *cache = Some((Instant::now(), Arc::clone(&jwks)));
+                        }
+                        find_key(&jwks, kid)
+                    }
+                    // Keeps accepting tokens while the identity provider is unavailable
+                    Err(err) => match cached {
+                        Some((_, jwks)) => {
+                            log::warn!("{err}. Using previously fetched keys");
+                            find_key(&jwks, kid)
+                        }
+                        None => Err(err),
+                    },
+                }
+            }
+        }
+    }
+}
+
+async fn fetch(client: &reqwest::Client, url: &str) -> Result<JwkSet, String> {
+    client
+        .get(url)
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(|err| format!("Cannot request JWKS URL '{url}': {err}"))?
+        .json::<JwkSet>()
+        .await
+        .map_err(|err| format!("Invalid JWKS from URL '{url}': {err}"))
+}
+
+fn find_key(jwks: &JwkSet, kid: Option<&str>) -> Result<Jwk, String> {
+    let jwk = match kid {
+        Some(kid) => jwks.find(kid),
+        None if jwks.keys.len() == 1 => jwks.keys.first(),
+        None => None,
+    };
+    jwk.cloned()
+        .ok_or_else(|| format!("Unknown key '{}'", kid.unwrap_or_default()))
+}
+
+#[derive(Default, Deserialize)]
+struct Roles {
+    #[serde(default)]
+    roles: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    sub: String,
+    #[serde(default, alias = "scp")]
+    scope: String,
+    #[serde(default)]
+    realm_access: Roles,
+    #[serde(default)]
+    resource_access: HashMap<String, Roles>,
+}
+
+impl Claims {
+    /// Checks if the given value is contained in scopes, realm roles or roles of the given client
+    fn grants(&self, scope: &str, client: &str) -> bool {
+        self.scope.split_whitespace().any(|value| value == scope)
+            || self.realm_access.roles.iter().any(|role| role == scope)
+            || self
+                .resource_access
+                .get(client)
+                .is_some_and(|client| client.roles.iter().any(|role| role == scope))
+    }
+}
+
+/// Authentication using OAuth2/OIDC bearer tokens
+pub struct BearerAuth {
+    jwks: Jwks,
+    issuer: String,
+    audience: String,
+    post_scope: Option<String>,
+    delete_scope: Option<String>,
+}
+
+impl BearerAuth {
+    pub fn new(jwks: Jwks, issuer: &str, audience: &str) -> Self {
+        Self {
+            jwks,
+            issuer: issuer.to_string(),
+            audience: audience.to_string(),
+            post_scope: None,
+            delete_scope: None,
+        }
+    }
+
+    pub fn issuer(&self) -> &str {
+        &self.issuer
+    }
+
+    /// Sets scopes or roles required to send or delete MTB files
+    pub fn with_scopes(mut self, post_scope: Option<&str>, delete_scope: Option<&str>) -> Self {
+        self.post_scope = post_scope.map(ToString::to_string);
+        self.delete_scope = delete_scope.map(ToString::to_string);
+        self
+    }
+
+    /// Returns bearer authentication if a JWKS file or URL is configured
+    pub fn from_config() -> Result<Option<Self>, String> {
+        let jwks = match (&CONFIG.oidc_jwks_file, &CONFIG.oidc_jwks_url) {
+            (Some(file), _) => Jwks::read(file)?,
+            (None, Some(url)) => Jwks::remote(url, Duration::from_secs(CONFIG.oidc_jwks_cache_ttl)),
+            (None, None) => return Ok(None),
+        };
+        let (Some(issuer), Some(audience)) = (&CONFIG.oidc_issuer, &CONFIG.oidc_audience) else {
+            return Err("Bearer authentication requires issuer and audience".to_string());
+        };
+        Ok(Some(Self::new(jwks, issuer, audience).with_scopes(
+            CONFIG.oidc_post_scope.as_deref(),
+            CONFIG.oidc_delete_scope.as_deref(),
+        )))
+    }
+
+    /// Validates the bearer token and returns the subject, if the scope required for the
+    /// request method is granted
+    pub async fn authenticate(&self, token: &str, method: &Method) -> Result<String, AuthError> {
+        let claims = self.validate(token).await.map_err(|err| {
+            log::debug!("Invalid bearer token: {err}");
+            AuthError::Unauthorized
+        })?;
+
+        let required_scope = match *method {
+            Method::POST => self.post_scope.as_deref(),
+            Method::DELETE => self.delete_scope.as_deref(),
+            _ => None,
+        };
+        match required_scope {
+            Some(scope) if !claims.grants(scope, &self.audience) => {
+                Err(AuthError::Forbidden(claims.sub))
+            }
+            _ => Ok(claims.sub),
+        }
+    }
+
+    async fn validate(&self, token: &str) -> Result<Claims, String> {
+        let header: Header = decode_header(token).map_err(|err| err.to_string())?;
+        if !ALGORITHMS.contains(&header.alg) {
+            return Err(format!("Unsupported algorithm {:?}", header.alg));
+        }
+
+        let jwk = self.jwks.find(header.kid.as_deref()).await?;
+        let key = DecodingKey::from_jwk(&jwk).map_err(|err| err.to_string())?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&self.issuer]);
+        validation.set_audience(&[&self.audience]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+
+        decode::<Claims>(token, &key, &validation)
+            .map(|data| data.claims)
+            .map_err(|err| err.to_string())
+    }
+}
+
+/// Returns the token of a HTTP bearer auth header
+pub fn bearer_token(auth_header: &str) -> Option<&str> {
+    let (scheme, token) = auth_header.split_once(' ')?;
+    scheme
+        .eq_ignore_ascii_case("bearer")
+        .then_some(token.trim())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::auth::AuthError;
+    use crate::oidc::{BearerAuth, Jwks, bearer_token};
+    use axum::Router;
+    use axum::http::{Method, StatusCode};
+    use axum::routing::get;
+    use jsonwebtoken::jwk::{Jwk, JwkSet};
+    use jsonwebtoken::{Algorithm, EncodingKey, Header, encode, get_current_timestamp};
+    use serde_json::{Value, json};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+
+    const ISSUER: &str = "https://iam.example.com/realms/dnpm";
+    const AUDIENCE: &str = "mv64e-rest-to-kafka-gateway";
+
+    /// Generates a new key pair and returns the encoding key and the public JWKS
+    #[allow(clippy::expect_used)]
+    fn key_pair() -> (EncodingKey, JwkSet) {
+        let key_pair = rcgen::KeyPair::generate().expect("key pair generated");
+        let encoding_key =
+            EncodingKey::from_ec_pem(key_pair.serialize_pem().as_bytes()).expect("valid key");
+        let mut jwk = Jwk::from_encoding_key(&encoding_key, Algorithm::ES256).expect("JWK");
+        jwk.common.key_id = Some("test-key".to_string());
+        (encoding_key, JwkSet { keys: vec![jwk] })
+    }
+
+    #[allow(clippy::expect_used)]
+    fn token(encoding_key: &EncodingKey, claims: &Value) -> String {
+        let mut header = Header::new(Algorithm::ES256);
+        header.kid = Some("test-key".to_string());
+        encode(&header, claims, encoding_key).expect("token encoded")
+    }
+
+    fn claims() -> Value {
+        json!({
+            "iss": ISSUER,
+            "aud": AUDIENCE,
+            "sub": "lab-import",
+            "exp": get_current_timestamp() + 300,
+            "scope": "profile mtb:write",
+            "realm_access": { "roles": ["mtb-delete"] }
+        })
+    }
+
+    async fn authenticate(claims: &Value, method: &Method) -> Result<String, AuthError> {
+        let (encoding_key, jwks) = key_pair();
+        let bearer_auth = BearerAuth::new(Jwks::Static(jwks), ISSUER, AUDIENCE)
+            .with_scopes(Some("mtb:write"), Some("mtb-delete"));
+        bearer_auth
+            .authenticate(&token(&encoding_key, claims), method)
+            .await
+    }
+
+    #[tokio::test]
+    async fn should_accept_valid_token() {
+        assert_eq!(
+            authenticate(&claims(), &Method::POST).await,
+            Ok("lab-import".to_string())
+        );
+        assert_eq!(
+            authenticate(&claims(), &Method::DELETE).await,
+            Ok("lab-import".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn should_reject_token_with_other_issuer() {
+        let mut claims = claims();
+        claims["iss"] = json!("https://iam.example.com/realms/other");
+        assert_eq!(
+            authenticate(&claims, &Method::POST).await,
+            Err(AuthError::Unauthorized)
+        );
+    }
+
+    #[tokio::test]
+    async fn should_reject_token_with_other_audience() {
+        let mut claims = claims();
+        claims["aud"] = json!("other-client");
+        assert_eq!(
+            authenticate(&claims, &Method::POST).await,
+            Err(AuthError::Unauthorized)
+        );
+    }
+
+    #[tokio::test]
+    async fn should_reject_expired_token() {
+        let mut claims = claims();
+        claims["exp"] = json!(get_current_timestamp() - 3600);
+        assert_eq!(
+            authenticate(&claims, &Method::POST).await,
+            Err(AuthError::Unauthorized)
+        );
+    }
+
+    #[tokio::test]
+    async fn should_reject_token_signed_with_other_key() {
+        let (encoding_key, _) = key_pair();
+        let (_, jwks) = key_pair();
+        let bearer_auth = BearerAuth::new(Jwks::Static(jwks), ISSUER, AUDIENCE);
+        assert_eq!(
+            bearer_auth
+                .authenticate(&token(&encoding_key, &claims()), &Method::POST)
+                .await,
+            Err(AuthError::Unauthorized)
+        );
+    }
+
+    #[tokio::test]
+    async fn should_reject_token_without_required_scope() {
+        let mut claims = claims();
+        claims["scope"] = json!("profile");
+        claims["realm_access"]["roles"] = json!([]);
+        claims["resource_access"] = json!({ AUDIENCE: { "roles": ["mtb-delete"] } });
+
+        assert_eq!(
+            authenticate(&claims, &Method::POST).await,
+            Err(AuthError::Forbidden("lab-import".to_string()))
+        );
+        assert_eq!(
+            authenticate(&claims, &Method::DELETE).await,
+            Ok("lab-import".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn should_ignore_roles_of_other_clients() {
+        let mut claims = claims();
+        claims["realm_access"]["roles"] = json!([]);
+        claims["resource_access"] = json!({ "other-client": { "roles": ["mtb-delete"] } });
+
+        assert_eq!(
+            authenticate(&claims, &Method::DELETE).await,
+            Err(AuthError::Forbidden("lab-import".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    #[allow(clippy::expect_used)]
+    async fn should_use_keys_from_jwks_url() {
+        let (encoding_key, jwks) = key_pair();
+        let jwks = serde_json::to_string(&jwks).expect("JWKS serialized");
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("listener bound");
+        let url = format!(
+            "http://{}/certs",
+            listener.local_addr().expect("local address")
+        );
+        let app = Router::new().route("/certs", get(move || async move { jwks }));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let bearer_auth =
+            BearerAuth::new(Jwks::remote(&url, Duration::from_mins(5)), ISSUER, AUDIENCE);
+        assert_eq!(
+            bearer_auth
+                .authenticate(&token(&encoding_key, &claims()), &Method::POST)
+                .await,
+            Ok("lab-import".to_string())
+        );
+    }
+
+    #[tokio::test]
+    #[allow(clippy::expect_used)]
+    async fn should_use_previously_fetched_keys_if_jwks_url_is_unavailable() {
+        let (encoding_key, jwks) = key_pair();
+        let jwks = serde_json::to_string(&jwks).expect("JWKS serialized");
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("listener bound");
+        let url = format!(
+            "http://{}/certs",
+            listener.local_addr().expect("local address")
+        );
+        let available = Arc::new(AtomicBool::new(true));
+        let app = Router::new().route(
+            "/certs",
+            get({
+                let available = Arc::clone(&available);
+                move || async move {
+                    if available.load(Ordering::SeqCst) {
+                        Ok(jwks)
+                    } else {
+                        Err(StatusCode::SERVICE_UNAVAILABLE)
+                    }
+                }
+            }),
+        );
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let bearer_auth = BearerAuth::new(Jwks::remote(&url, Duration::ZERO), ISSUER, AUDIENCE);
+        let token = token(&encoding_key, &claims());
+        assert_eq!(
+            bearer_auth.authenticate(&token, &Method::POST).await,
+            Ok("lab-import".to_string())
+        );
+
+        available.store(false, Ordering::SeqCst);
+        assert_eq!(
+            bearer_auth.authenticate(&token, &Method::POST).await,
+            Ok("lab-import".to_string())
+        );
+    }
+
+    #[test]
+    fn should_extract_bearer_token() {
+        assert_eq!(bearer_token("Bearer abc.def.ghi"), Some("abc.def.ghi"));
+        assert_eq!(bearer_token("bearer abc.def.ghi"), Some("abc.def.ghi"));
+        assert_eq!(bearer_token("Basic dG9rZW46dmVyeS1zZWNyZXQ="), None);
+    }
+}
@@ -0,0 +1,721 @@
+use async_trait::async_trait;
+use clap::ValueEnum;
+use mv64e_mtb_dto::Mtb;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::sync::{Notify, oneshot};
+
+use crate::sender::{
+    DefaultMtbFileSender, Delivery, MtbFileSender, MtbRecord, SendOptions, SendReport, is_permanent,
+};
+use crate::unchanged::ContentHashStore;
+
+const SEGMENT_SIZE: u64 = 16 * 1024 * 1024;
+const SEGMENT_EXTENSION: &str = "segment";
+const CURSOR_FILE: &str = "cursor";
+const DEAD_LETTER_FILE: &str = "dead-letter.jsonl";
+
+const MIN_RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum FsyncPolicy {
+    /// Sync each record to disk before the request is accepted
+    Always,
+    /// Sync pending records to disk once per second
+    Interval,
+    /// Leave syncing to the operating system
+    Never,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Position {
+    segment: u64,
+    offset: u64,
+}
+
+struct State {
+    writer: File,
+    write: Position,
+    read: Position,
+    size: u64,
+    unsynced: bool,
+}
+
+/// Append-only persistent queue of records not yet sent to Kafka.
+///
+/// Records are stored as JSON lines in numbered segment files. The position of the next
+/// record to be sent is kept in a cursor file, segments already sent completely are removed.
+/// Records that cannot be read or sent are moved to a dead letter file.
+pub struct Outbox {
+    dir: PathBuf,
+    max_size: u64,
+    segment_size: u64,
+    fsync: FsyncPolicy,
+    state: Mutex<State>,
+    notify: Notify,
+    deliveries: Mutex<HashMap<String, oneshot::Sender<Delivery>>>,
+}
+
+pub struct OutboxEntry {
+    pub record: MtbRecord,
+    next: Position,
+}
+
+impl Outbox {
+    pub fn open(dir: impl AsRef<Path>, max_size: u64, fsync: FsyncPolicy) -> io::Result<Self> {
+        Self::open_with_segment_size(dir, max_size, SEGMENT_SIZE, fsync)
+    }
+
+    fn open_with_segment_size(
+        dir: impl AsRef<Path>,
+        max_size: u64,
+        segment_size: u64,
+        fsync: FsyncPolicy,
+    ) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let mut read = read_cursor(&dir)?;
+        let mut segments = list_segments(&dir)?;
+
+        // Segments before the cursor have been sent completely
+        for segment in segments.iter().filter(|segment| **segment < read.segment) {
+            fs::remove_file(segment_path(&dir, *segment))?;
+        }
+        segments.retain(|segment| *segment >= read.segment);
+
+        if segments.first().is_some_and(|first| *first > read.segment) {
+            read = Position {
+                segment: segments[0],
+                offset: 0,
+            };
+        }
+
+        let write_segment = segments.last().copied().unwrap_or(read.segment);
+        let write_offset = recover_segment(&segment_path(&dir, write_segment))?;
+
+        let mut size = write_offset;
+        for segment in segments.iter().filter(|segment| **segment != write_segment) {
+            size += fs::metadata(segment_path(&dir, *segment))?.len();
+        }
+        if read.segment == write_segment {
+            read.offset = read.offset.min(write_offset);
+        }
+        size = size.saturating_sub(read.offset);
+
+        Ok(Self {
+            dir: dir.clone(),
+            max_size,
+            segment_size,
+            fsync,
+            state: Mutex::new(State {
+                writer: open_segment_writer(&dir, write_segment)?,
+                write: Position {
+                    segment: write_segment,
+                    offset: write_offset,
+                },
+                read,
+                size,
+                unsynced: false,
+            }),
+            notify: Notify::new(),
+            deliveries: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Returns the size of all records not yet sent in bytes
+    pub fn size(&self) -> u64 {
+        self.lock().map(|state| state.size).unwrap_or_default()
+    }
+
+    /// Returns the maximum size of all records not yet sent in bytes
+    pub fn max_size(&self) -> u64 {
+        self.max_size
+    }
+
+    pub fn append(&self, record: &MtbRecord) -> io::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        let len = line.len() as u64;
+
+        let mut state = self.lock()?;
+        if state.size + len > self.max_size {
+            return Err(io::Error::new(
+                io::ErrorKind::StorageFull,
+                "maximum outbox size reached",
+            ));
+        }
+
+        if state.write.offset > 0 && state.write.offset + len > self.segment_size {
+            self.roll(&mut state)?;
+        }
+
+        if let Err(err) = state.writer.write_all(&line) {
+            // Remove partially written record
+            let _ = state.writer.set_len(state.write.offset);
+            return Err(err);
+        }
+        if self.fsync == FsyncPolicy::Always {
+            state.writer.sync_data()?;
+        } else {
+            state.unsynced = true;
+        }
+
+        state.write.offset += len;
+        state.size += len;
+        drop(state);
+
+        self.notify.notify_one();
+        Ok(())
+    }
+
+    /// Returns the next record to be sent without removing it from the outbox. Unreadable
+    /// records are moved to the dead letter file.
+    pub fn peek(&self) -> io::Result<Option<OutboxEntry>> {
+        let mut state = self.lock()?;
+        loop {
+            if state.read == state.write {
+                return Ok(None);
+            }
+
+            let path = segment_path(&self.dir, state.read.segment);
+            let mut reader = BufReader::new(File::open(&path)?);
+            reader.seek(SeekFrom::Start(state.read.offset))?;
+
+            let mut line = Vec::new();
+            let len = reader.read_until(b'\n', &mut line)? as u64;
+            if len > 0 {
+                let next = Position {
+                    segment: state.read.segment,
+                    offset: state.read.offset + len,
+                };
+                match serde_json::from_slice(&line) {
+                    Ok(record) => return Ok(Some(OutboxEntry { record, next })),
+                    Err(err) => {
+                        log::error!("Moving unreadable outbox record to dead letter file: {err}");
+                        if line.last() != Some(&b'\n') {
+                            line.push(b'\n');
+                        }
+                        self.append_dead_letter(&line)?;
+                        self.write_cursor(next)?;
+                        state.size = state.size.saturating_sub(len);
+                        state.read = next;
+                        continue;
+                    }
+                }
+            }
+
+            if state.read.segment >= state.write.segment {
+                return Ok(None);
+            }
+
+            // Segment has been sent completely
+            let next = Position {
+                segment: state.read.segment + 1,
+                offset: 0,
+            };
+            self.write_cursor(next)?;
+            fs::remove_file(path)?;
+            state.read = next;
+        }
+    }
+
+    /// Removes the given record, previously returned by `peek()`, from the outbox
+    pub fn commit(&self, entry: &OutboxEntry) -> io::Result<()> {
+        let mut state = self.lock()?;
+        self.write_cursor(entry.next)?;
+        state.size = state
+            .size
+            .saturating_sub(entry.next.offset.saturating_sub(state.read.offset));
+        state.read = entry.next;
+        Ok(())
+    }
+
+    /// Moves the given record, previously returned by `peek()`, to the dead letter file
+    pub fn dead_letter(&self, entry: &OutboxEntry) -> io::Result<()> {
+        let mut line = serde_json::to_vec(&entry.record)?;
+        line.push(b'\n');
+        self.append_dead_letter(&line)?;
+        self.commit(entry)
+    }
+
+    fn append_dead_letter(&self, line: &[u8]) -> io::Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dir.join(DEAD_LETTER_FILE))?;
+        file.write_all(line)?;
+        if self.fsync != FsyncPolicy::Never {
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    pub fn sync(&self) -> io::Result<()> {
+        let mut state = self.lock()?;
+        if state.unsynced {
+            state.writer.sync_data()?;
+            state.unsynced = false;
+        }
+        Ok(())
+    }
+
+    /// Returns a receiver for the delivery of the record with given request id
+    fn subscribe_delivery(&self, request_id: &str) -> oneshot::Receiver<Delivery> {
+        let (sender, receiver) = oneshot::channel();
+        if let Ok(mut deliveries) = self.deliveries.lock() {
+            deliveries.insert(request_id.to_string(), sender);
+        }
+        receiver
+    }
+
+    fn unsubscribe_delivery(&self, request_id: &str) {
+        if let Ok(mut deliveries) = self.deliveries.lock() {
+            deliveries.remove(request_id);
+        }
+    }
+
+    fn delivered(&self, request_id: &str, delivery: Delivery) {
+        if let Ok(mut deliveries) = self.deliveries.lock()
+            && let Some(sender) = deliveries.remove(request_id)
+        {
+            let _ = sender.send(delivery);
+        }
+    }
+
+    fn roll(&self, state: &mut State) -> io::Result<()> {
+        if self.fsync != FsyncPolicy::Never {
+            state.writer.sync_data()?;
+        }
+        let segment = state.write.segment + 1;
+        state.writer = open_segment_writer(&self.dir, segment)?;
+        state.write = Position { segment, offset: 0 };
+        state.unsynced = false;
+        Ok(())
+    }
+
+    fn write_cursor(&self, position: Position) -> io::Result<()> {
+        let tmp_path = self.dir.join(format!("{CURSOR_FILE}.tmp"));
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(format!("{} {}", position.segment, position.offset).as_bytes())?;
+        if self.fsync == FsyncPolicy::Always {
+            file.sync_data()?;
+        }
+        fs::rename(tmp_path, self.dir.join(CURSOR_FILE))
+    }
+
+    fn lock(&self) -> io::Result<MutexGuard<'_, State>> {
+        self.state
+            .lock()
+            .map_err(|_| io::Error::other("outbox state is poisoned"))
+    }
+}
+
+fn segment_path(dir: &Path, segment: u64) -> PathBuf {
+    dir.join(format!("{segment:020}.{SEGMENT_EXTENSION}"))
+}
+
+fn open_segment_writer(dir: &Path, segment: u64) -> io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(segment_path(dir, segment))
+}
+
+fn list_segments(dir: &Path) -> io::Result<Vec<u64>> {
+    let mut segments = fs::read_dir(dir)?
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|extension| extension == SEGMENT_EXTENSION)
+        })
+        .filter_map(|path| path.file_stem()?.to_str()?.parse::<u64>().ok())
+        .collect::<Vec<_>>();
+    segments.sort_unstable();
+    Ok(segments)
+}
+
+fn read_cursor(dir: &Path) -> io::Result<Position> {
+    let content = match fs::read_to_string(dir.join(CURSOR_FILE)) {
+        Ok(content) => content,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            return Ok(Position {
+                segment: 0,
+                offset: 0,
+            });
+        }
+        Err(err) => return Err(err),
+    };
+
+    let invalid_cursor = || io::Error::new(io::ErrorKind::InvalidData, "invalid outbox cursor");
+    let (segment, offset) = content.trim().split_once(' ').ok_or_else(invalid_cursor)?;
+    Ok(Position {
+        segment: segment.parse().map_err(|_| invalid_cursor())?,
+        offset: offset.parse().map_err(|_| invalid_cursor())?,
+    })
+}
+
+/// Truncates an incomplete last record, e.g. after a crash while writing. Complete but
+/// unreadable records are kept and moved to the dead letter file when read by `peek()`.
+/// Returns the size of the segment containing complete records only.
+fn recover_segment(path: &Path) -> io::Result<u64> {
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .read(true)
+        .write(true)
+        .open(path)?;
+    let len = file.metadata()?.len();
+
+    let mut reader = BufReader::new(&file);
+    let mut valid = 0;
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        let read = reader.read_until(b'\n', &mut line)? as u64;
+        if read == 0 || line.last() != Some(&b'\n') {
+            break;
+        }
+        valid += read;
+    }
+
+    if valid < len {
+        log::warn!(
+            "Removing {} bytes of incomplete outbox record from '{}'",
+            len - valid,
+            path.display()
+        );
+        file.set_len(valid)?;
+    }
+
+    Ok(valid)
+}
+
+/// Runs a blocking operation of the outbox, e.g. reading or syncing files
+async fn run_blocking<T, F>(outbox: &Arc<Outbox>, operation: F) -> io::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&Outbox) -> io::Result<T> + Send + 'static,
+{
+    let outbox = Arc::clone(outbox);
+    tokio::task::spawn_blocking(move || operation(&outbox))
+        .await
+        .map_err(io::Error::other)?
+}
+
+/// Sends records stored in the outbox to Kafka in order, retrying until Kafka is available.
+/// Records that can never be sent, e.g. if too large, are moved to the dead letter file and
+/// their content hashes are removed, so the MTB files are not skipped as unchanged.
+pub async fn forward(
+    outbox: Arc<Outbox>,
+    sender: DefaultMtbFileSender,
+    content_hashes: Option<Arc<ContentHashStore>>,
+) {
+    let mut retry_delay = MIN_RETRY_DELAY;
+    loop {
+        let entry = match run_blocking(&outbox, Outbox::peek).await {
+            Ok(Some(entry)) => entry,
+            Ok(None) => {
+                outbox.notify.notified().await;
+                continue;
+            }
+            Err(err) => {
+                log::error!("Cannot read record from outbox: {err}");
+                tokio::time::sleep(retry_delay).await;
+                retry_delay = (retry_delay * 2).min(MAX_RETRY_DELAY);
+                continue;
+            }
+        };
+        let request_id = entry.record.request_id.clone();
+
+        let delivery = match sender.send_record(&entry.record).await {
+            Ok(delivery) => delivery,
+            Err(err) if is_permanent(&err) => {
+                log::error!(
+                    "Cannot send record '{request_id}' to Kafka - moving it to dead letter file: {err}"
+                );
+                retry_delay = MIN_RETRY_DELAY;
+                outbox.unsubscribe_delivery(&request_id);
+                if let Some(content_hashes) = &content_hashes {
+                    remove_content_hash(content_hashes, &request_id).await;
+                }
+                if let Err(err) =
+                    run_blocking(&outbox, move |outbox| outbox.dead_letter(&entry)).await
+                {
+                    log::error!("Cannot move record '{request_id}' to dead letter file: {err}");
+                    tokio::time::sleep(MAX_RETRY_DELAY).await;
+                }
+                continue;
+            }
+            Err(err) => {
+                log::warn!(
+                    "Cannot send record '{request_id}' to Kafka - retrying in {}s: {err}",
+                    retry_delay.as_secs()
+                );
+                tokio::time::sleep(retry_delay).await;
+                retry_delay = (retry_delay * 2).min(MAX_RETRY_DELAY);
+                continue;
+            }
+        };
+
+        retry_delay = MIN_RETRY_DELAY;
+        outbox.delivered(&request_id, delivery);
+        if let Err(err) = run_blocking(&outbox, move |outbox| outbox.commit(&entry)).await {
+            log::error!("Cannot remove record '{request_id}' from outbox: {err}");
+            tokio::time::sleep(MAX_RETRY_DELAY).await;
+        }
+    }
+}
+
+async fn remove_content_hash(content_hashes: &Arc<ContentHashStore>, request_id: &str) {
+    let content_hashes = Arc::clone(content_hashes);
+    let request_id = request_id.to_string();
+    match tokio::task::spawn_blocking(move || content_hashes.remove(&request_id)).await {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => log::warn!("Cannot remove content hash of MTB file: {err}"),
+        Err(_) => log::warn!("Cannot remove content hash of MTB file"),
+    }
+}
+
+pub async fn sync_periodically(outbox: Arc<Outbox>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        if let Err(err) = run_blocking(&outbox, Outbox::sync).await {
+            log::error!("Cannot sync outbox to disk: {err}");
+        }
+    }
+}
+
+#[allow(clippy::module_name_repetitions)]
+pub struct OutboxMtbFileSender {
+    outbox: Arc<Outbox>,
+}
+
+impl OutboxMtbFileSender {
+    pub fn new(outbox: Arc<Outbox>) -> Self {
+        Self { outbox }
+    }
+}
+
+#[async_trait]
+impl MtbFileSender for OutboxMtbFileSender {
+    async fn send(&self, mtb: Mtb, options: SendOptions) -> Result<SendReport, ()> {
+        let record = MtbRecord::new(&mtb, &options)?;
+        let request_id = record.request_id.clone();
+
+        let delivery = options
+            .wait_for_delivery
+            .then(|| self.outbox.subscribe_delivery(&request_id));
+
+        let outbox = Arc::clone(&self.outbox);
+        match tokio::task::spawn_blocking(move || outbox.append(&record)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => {
+                log::error!("Cannot store record '{request_id}' in outbox: {err}");
+                self.outbox.unsubscribe_delivery(&request_id);
+                return Err(());
+            }
+            Err(_) => {
+                self.outbox.unsubscribe_delivery(&request_id);
+                return Err(());
+            }
+        }
+
+        let Some(delivery) = delivery else {
+            return Ok(SendReport::accepted(&request_id));
+        };
+
+        // Record is kept in outbox if not sent in time
+        if let Ok(Ok(delivery)) = tokio::time::timeout(DELIVERY_TIMEOUT, delivery).await {
+            return Ok(SendReport {
+                request_id,
+                delivery: Some(delivery),
+                unchanged: false,
+            });
+        }
+
+        self.outbox.unsubscribe_delivery(&request_id);
+        Ok(SendReport::accepted(&request_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::outbox::{DEAD_LETTER_FILE, FsyncPolicy, Outbox, segment_path};
+    use crate::sender::MtbRecord;
+    use crate::telemetry::TraceContext;
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+
+    fn record(request_id: &str) -> MtbRecord {
+        MtbRecord {
+            request_id: request_id.to_string(),
+            key: "{\"pid\":\"P1\"}".to_string(),
+            payload: "{\"patient\":{\"id\":\"P1\"}}".to_string(),
+            username: None,
+            warnings: vec![],
+            topic: None,
+            trace: TraceContext::default(),
+            source_headers: vec![],
+        }
+    }
+
+    #[test]
+    #[allow(clippy::unwrap_used)]
+    fn should_return_records_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let outbox = Outbox::open(dir.path(), 1024 * 1024, FsyncPolicy::Always).unwrap();
+
+        outbox.append(&record("1")).unwrap();
+        outbox.append(&record("2")).unwrap();
+
+        let entry = outbox.peek().unwrap().unwrap();
+        assert_eq!(entry.record.request_id, "1");
+        outbox.commit(&entry).unwrap();
+
+        let entry = outbox.peek().unwrap().unwrap();
+        assert_eq!(entry.record.request_id, "2");
+        outbox.commit(&entry).unwrap();
+
+        assert!(outbox.peek().unwrap().is_none());
+        assert_eq!(outbox.size(), 0);
+    }
+
+    #[test]
+    #[allow(clippy::unwrap_used)]
+    fn should_keep_pending_records_after_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let outbox = Outbox::open(dir.path(), 1024 * 1024, FsyncPolicy::Always).unwrap();
+            outbox.append(&record("1")).unwrap();
+            outbox.append(&record("2")).unwrap();
+            let entry = outbox.peek().unwrap().unwrap();
+            outbox.commit(&entry).unwrap();
+        }
+
+        let outbox = Outbox::open(dir.path(), 1024 * 1024, FsyncPolicy::Always).unwrap();
+        let entry = outbox.peek().unwrap().unwrap();
+        assert_eq!(entry.record.request_id, "2");
+    }
+
+    #[test]
+    #[allow(clippy::unwrap_used)]
+    fn should_remove_incomplete_record_on_recovery() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let outbox = Outbox::open(dir.path(), 1024 * 1024, FsyncPolicy::Always).unwrap();
+            outbox.append(&record("1")).unwrap();
+        }
+        OpenOptions::new()
+            .append(true)
+            .open(segment_path(dir.path(), 0))
+            .unwrap()
+            .write_all(b"{\"request_id\":\"2\",\"ke")
+            .unwrap();
+
+        let outbox = Outbox::open(dir.path(), 1024 * 1024, FsyncPolicy::Always).unwrap();
+        outbox.append(&record("3")).unwrap();
+
+        let entry = outbox.peek().unwrap().unwrap();
+        assert_eq!(entry.record.request_id, "1");
+        outbox.commit(&entry).unwrap();
+        let entry = outbox.peek().unwrap().unwrap();
+        assert_eq!(entry.record.request_id, "3");
+    }
+
+    #[test]
+    #[allow(clippy::unwrap_used)]
+    fn should_keep_records_after_unreadable_record_on_recovery() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let outbox = Outbox::open(dir.path(), 1024 * 1024, FsyncPolicy::Always).unwrap();
+            outbox.append(&record("1")).unwrap();
+        }
+        OpenOptions::new()
+            .append(true)
+            .open(segment_path(dir.path(), 0))
+            .unwrap()
+            .write_all(b"{\"request_id\":\"2\",\"ke\n")
+            .unwrap();
+        {
+            let outbox = Outbox::open(dir.path(), 1024 * 1024, FsyncPolicy::Always).unwrap();
+            outbox.append(&record("3")).unwrap();
+        }
+
+        let outbox = Outbox::open(dir.path(), 1024 * 1024, FsyncPolicy::Always).unwrap();
+        let entry = outbox.peek().unwrap().unwrap();
+        assert_eq!(entry.record.request_id, "1");
+        outbox.commit(&entry).unwrap();
+        let entry = outbox.peek().unwrap().unwrap();
+        assert_eq!(entry.record.request_id, "3");
+
+        let dead_letters = fs::read_to_string(dir.path().join(DEAD_LETTER_FILE)).unwrap();
+        assert_eq!(dead_letters, "{\"request_id\":\"2\",\"ke\n");
+    }
+
+    #[test]
+    #[allow(clippy::unwrap_used)]
+    fn should_reject_record_if_outbox_is_full() {
+        let dir = tempfile::tempdir().unwrap();
+        let outbox = Outbox::open(dir.path(), 100, FsyncPolicy::Never).unwrap();
+
+        assert!(outbox.append(&record("1")).is_ok());
+        assert!(outbox.append(&record("2")).is_err());
+    }
+
+    #[test]
+    #[allow(clippy::unwrap_used)]
+    fn should_remove_sent_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let outbox =
+            Outbox::open_with_segment_size(dir.path(), 1024 * 1024, 10, FsyncPolicy::Never)
+                .unwrap();
+
+        outbox.append(&record("1")).unwrap();
+        outbox.append(&record("2")).unwrap();
+        assert!(segment_path(dir.path(), 1).exists());
+
+        let entry = outbox.peek().unwrap().unwrap();
+        outbox.commit(&entry).unwrap();
+        let entry = outbox.peek().unwrap().unwrap();
+        assert_eq!(entry.record.request_id, "2");
+        assert!(!segment_path(dir.path(), 0).exists());
+    }
+
+    #[test]
+    #[allow(clippy::unwrap_used)]
+    fn should_move_records_to_dead_letter_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let outbox =
+            Outbox::open_with_segment_size(dir.path(), 1024 * 1024, 10, FsyncPolicy::Never)
+                .unwrap();
+
+        outbox.append(&record("1")).unwrap();
+        outbox.append(&record("2")).unwrap();
+        outbox.append(&record("3")).unwrap();
+        // Corrupt record in a segment other than the last one
+        fs::write(segment_path(dir.path(), 1), b"{\"request_id\":\"2\",\"ke\n").unwrap();
+
+        let entry = outbox.peek().unwrap().unwrap();
+        assert_eq!(entry.record.request_id, "1");
+        outbox.dead_letter(&entry).unwrap();
+
+        let entry = outbox.peek().unwrap().unwrap();
+        assert_eq!(entry.record.request_id, "3");
+
+        let dead_letters = fs::read_to_string(dir.path().join(DEAD_LETTER_FILE)).unwrap();
+        let lines = dead_letters.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].contains("\"request_id\":\"1\""));
+        assert_eq!(lines[1], "{\"request_id\":\"2\",\"ke");
+    }
+}
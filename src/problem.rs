@@ -0,0 +1,226 @@
+use axum::Json;
+use axum::http::StatusCode;
+use axum::http::header::CONTENT_TYPE;
+use axum::response::{IntoResponse, Response};
+use serde::{Serialize, Serializer};
+use uuid::Uuid;
+
+const PROBLEM_TYPE_BASE: &str = "urn:mv64e-rest-to-kafka-gateway:problem:";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProblemType {
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    IdempotencyConflict,
+    RequestIdConflict,
+    UnsupportedContentType,
+    PayloadTooLarge,
+    InvalidJson,
+    InvalidIdempotencyKey,
+    InvalidRequestId,
+    InvalidMtbFile,
+    ValidationFailed,
+    TransactionAborted,
+    DeliveryFailed,
+}
+
+impl ProblemType {
+    pub fn name(self) -> &'static str {
+        match self {
+            ProblemType::Unauthorized => "unauthorized",
+            ProblemType::Forbidden => "forbidden",
+            ProblemType::NotFound => "not-found",
+            ProblemType::IdempotencyConflict => "idempotency-conflict",
+            ProblemType::RequestIdConflict => "request-id-conflict",
+            ProblemType::UnsupportedContentType => "unsupported-content-type",
+            ProblemType::PayloadTooLarge => "payload-too-large",
+            ProblemType::InvalidJson => "invalid-json",
+            ProblemType::InvalidIdempotencyKey => "invalid-idempotency-key",
+            ProblemType::InvalidRequestId => "invalid-request-id",
+            ProblemType::InvalidMtbFile => "invalid-mtb-file",
+            ProblemType::ValidationFailed => "validation-failed",
+            ProblemType::TransactionAborted => "transaction-aborted",
+            ProblemType::DeliveryFailed => "delivery-failed",
+        }
+    }
+
+    fn title(self) -> &'static str {
+        match self {
+            ProblemType::Unauthorized => "Unauthorized",
+            ProblemType::Forbidden => "Forbidden",
+            ProblemType::NotFound => "Not found",
+            ProblemType::IdempotencyConflict => "Idempotency conflict",
+            ProblemType::RequestIdConflict => "Request ID conflict",
+            ProblemType::UnsupportedContentType => "Unsupported content type",
+            ProblemType::PayloadTooLarge => "Payload too large",
+            ProblemType::InvalidJson => "Invalid JSON",
+            ProblemType::InvalidIdempotencyKey => "Invalid idempotency key",
+            ProblemType::InvalidRequestId => "Invalid request ID",
+            ProblemType::InvalidMtbFile => "Invalid MTB file",
+            ProblemType::ValidationFailed => "Validation failed",
+            ProblemType::TransactionAborted => "Transaction aborted",
+            ProblemType::DeliveryFailed => "Delivery failed",
+        }
+    }
+
+    pub fn status(self) -> StatusCode {
+        match self {
+            ProblemType::Unauthorized => StatusCode::UNAUTHORIZED,
+            ProblemType::Forbidden => StatusCode::FORBIDDEN,
+            ProblemType::NotFound => StatusCode::NOT_FOUND,
+            ProblemType::IdempotencyConflict | ProblemType::RequestIdConflict => {
+                StatusCode::CONFLICT
+            }
+            ProblemType::UnsupportedContentType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ProblemType::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ProblemType::InvalidJson
+            | ProblemType::InvalidIdempotencyKey
+            | ProblemType::InvalidRequestId => StatusCode::BAD_REQUEST,
+            ProblemType::InvalidMtbFile | ProblemType::ValidationFailed => {
+                StatusCode::UNPROCESSABLE_ENTITY
+            }
+            ProblemType::TransactionAborted => StatusCode::FAILED_DEPENDENCY,
+            ProblemType::DeliveryFailed => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// Serialized as URI of the problem type
+impl Serialize for ProblemType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&format_args!("{PROBLEM_TYPE_BASE}{}", self.name()))
+    }
+}
+
+/// Offending field in a request body
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct FieldError {
+    pub path: String,
+    pub message: String,
+}
+
+/// Problem details as specified in RFC 9457
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Problem {
+    #[serde(rename = "type")]
+    kind: ProblemType,
+    pub title: &'static str,
+    pub status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    pub request_id: String,
+    /// Exceeded limit of the request body size in bytes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<FieldError>,
+}
+
+impl Problem {
+    pub fn new(problem_type: ProblemType) -> Self {
+        Self {
+            kind: problem_type,
+            title: problem_type.title(),
+            status: problem_type.status().as_u16(),
+            detail: None,
+            request_id: Uuid::new_v4().to_string(),
+            limit: None,
+            errors: vec![],
+        }
+    }
+
+    /// Uses the ID of the rejected request, e.g. given by the client, instead of a generated one
+    pub fn with_request_id(mut self, request_id: &str) -> Self {
+        self.request_id = request_id.to_string();
+        self
+    }
+
+    pub fn with_detail(mut self, detail: &str) -> Self {
+        self.detail = Some(detail.to_string());
+        self
+    }
+
+    pub fn with_limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    pub fn with_errors(mut self, errors: Vec<FieldError>) -> Self {
+        self.errors = errors;
+        self
+    }
+}
+
+impl IntoResponse for Problem {
+    fn into_response(self) -> Response {
+        // Logged to find the rejected request using the request ID given to the client
+        log::info!(
+            "Rejected request '{}': {}{}",
+            self.request_id,
+            self.kind.name(),
+            self.detail
+                .as_deref()
+                .map(|detail| format!(" - {detail}"))
+                .unwrap_or_default()
+        );
+
+        let kind = self.kind;
+        let mut response = (
+            StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
+            [(CONTENT_TYPE, "application/problem+json")],
+            Json(self),
+        )
+            .into_response();
+        // Used to track the reason of rejected requests
+        response.extensions_mut().insert(kind);
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::problem::{FieldError, Problem, ProblemType};
+    use axum::http::header::CONTENT_TYPE;
+    use axum::http::{HeaderValue, StatusCode};
+    use axum::response::IntoResponse;
+    use http_body_util::BodyExt;
+
+    #[tokio::test]
+    #[allow(clippy::expect_used)]
+    async fn should_return_problem_details() {
+        let response = Problem::new(ProblemType::InvalidMtbFile)
+            .with_detail("Test")
+            .with_errors(vec![FieldError {
+                path: "$.patient.id".to_string(),
+                message: "missing field `id`".to_string(),
+            }])
+            .into_response();
+
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(
+            response
+                .headers()
+                .get(CONTENT_TYPE)
+                .map(HeaderValue::as_bytes),
+            Some(b"application/problem+json".as_slice())
+        );
+
+        let body = response
+            .into_body()
+            .collect()
+            .await
+            .expect("body collected")
+            .to_bytes();
+        let problem = serde_json::from_slice::<serde_json::Value>(&body).expect("JSON body");
+        assert_eq!(
+            problem["type"],
+            "urn:mv64e-rest-to-kafka-gateway:problem:invalid-mtb-file"
+        );
+        assert_eq!(problem["status"], 422);
+        assert_eq!(problem["detail"], "Test");
+        assert!(problem["requestId"].is_string());
+        assert_eq!(problem["errors"][0]["path"], "$.patient.id");
+    }
+}
@@ -0,0 +1,532 @@
+use async_trait::async_trait;
+use clap::ValueEnum;
+use hmac::{Hmac, Mac};
+use mv64e_mtb_dto::Mtb;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use sha2::Sha256;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::Write as _;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+#[cfg(test)]
+use mockall::automock;
+
+use crate::CONFIG;
+use crate::secrets::{SECRETS, Secret, SecretValue};
+use crate::sender::{DynMtbFileSender, MtbFileSender, SendOptions, SendReport};
+
+pub type DynPseudonymizer = Arc<dyn Pseudonymizer + Send + Sync>;
+
+/// Timeouts requesting gPAS, so an unresponsive gPAS instance does not block requests
+const GPAS_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const GPAS_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum PseudonymizerType {
+    /// Keyed HMAC-SHA256 of the patient ID
+    Hmac,
+    /// Random pseudonyms stored in a local mapping file
+    Mapping,
+    /// Pseudonyms created by a gPAS instance
+    Gpas,
+}
+
+#[cfg_attr(test, automock)]
+#[async_trait]
+pub trait Pseudonymizer {
+    /// Returns the pseudonym of the patient ID, always the same one for the same patient ID
+    async fn pseudonymize(&self, patient_id: &str) -> Result<String, String>;
+}
+
+/// Creates the configured pseudonymizer
+pub fn from_config() -> Result<Option<DynPseudonymizer>, String> {
+    let Some(pseudonymizer_type) = CONFIG.pseudonymizer else {
+        return Ok(None);
+    };
+
+    let pseudonymizer: DynPseudonymizer = match pseudonymizer_type {
+        PseudonymizerType::Hmac => {
+            let Some(key) = SECRETS.get(Secret::PseudonymHmacKey) else {
+                return Err("HMAC pseudonymization requires a key".to_string());
+            };
+            Arc::new(HmacPseudonymizer::new(key.as_bytes()))
+        }
+        PseudonymizerType::Mapping => {
+            let Some(file) = &CONFIG.pseudonym_mapping_file else {
+                return Err("Pseudonymization using a mapping requires a mapping file".to_string());
+            };
+            let mapping = MappingPseudonymizer::open(file)
+                .map_err(|err| format!("Cannot open pseudonym mapping file '{file}': {err}"))?;
+            Arc::new(mapping)
+        }
+        PseudonymizerType::Gpas => {
+            let (Some(url), Some(domain)) = (&CONFIG.gpas_url, &CONFIG.gpas_domain) else {
+                return Err("Pseudonymization using gPAS requires URL and domain".to_string());
+            };
+            let mut gpas = GpasPseudonymizer::new(url, domain);
+            if let Some(username) = &CONFIG.gpas_username {
+                // The password is looked up for each request as it might be replaced
+                gpas = gpas.with_basic_auth(
+                    username,
+                    Some(SecretValue::Configured(Secret::GpasPassword)),
+                );
+            }
+            Arc::new(gpas)
+        }
+    };
+    Ok(Some(pseudonymizer))
+}
+
+pub struct HmacPseudonymizer {
+    key: Zeroizing<Vec<u8>>,
+}
+
+impl HmacPseudonymizer {
+    pub fn new(key: &[u8]) -> Self {
+        Self {
+            key: Zeroizing::new(key.to_vec()),
+        }
+    }
+}
+
+#[async_trait]
+impl Pseudonymizer for HmacPseudonymizer {
+    async fn pseudonymize(&self, patient_id: &str) -> Result<String, String> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).map_err(|err| err.to_string())?;
+        mac.update(patient_id.as_bytes());
+        Ok(mac
+            .finalize()
+            .into_bytes()
+            .iter()
+            .fold(String::new(), |mut hex, byte| {
+                let _ = write!(hex, "{byte:02x}");
+                hex
+            }))
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct MappingEntry {
+    original: String,
+    pseudonym: String,
+}
+
+/// Assigns random pseudonyms and keeps them in an append-only JSON lines file
+pub struct MappingPseudonymizer {
+    mapping: Arc<Mutex<(HashMap<String, String>, File)>>,
+}
+
+impl MappingPseudonymizer {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+
+        let mut mapping = HashMap::new();
+        match File::open(path) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    let entry = serde_json::from_str::<MappingEntry>(&line?)?;
+                    mapping.insert(entry.original, entry.pseudonym);
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            mapping: Arc::new(Mutex::new((mapping, file))),
+        })
+    }
+}
+
+#[async_trait]
+impl Pseudonymizer for MappingPseudonymizer {
+    async fn pseudonymize(&self, patient_id: &str) -> Result<String, String> {
+        if let Some(pseudonym) = self
+            .mapping
+            .lock()
+            .map_err(|err| err.to_string())?
+            .0
+            .get(patient_id)
+        {
+            return Ok(pseudonym.clone());
+        }
+
+        // Storing a new pseudonym syncs the mapping file to disk
+        let mapping = Arc::clone(&self.mapping);
+        let patient_id = patient_id.to_string();
+        tokio::task::spawn_blocking(move || assign_pseudonym(&mapping, &patient_id))
+            .await
+            .map_err(|err| err.to_string())?
+    }
+}
+
+/// Returns the pseudonym of the patient ID, assigning and storing a new one if unknown
+fn assign_pseudonym(
+    mapping: &Mutex<(HashMap<String, String>, File)>,
+    patient_id: &str,
+) -> Result<String, String> {
+    let mut guard = mapping.lock().map_err(|err| err.to_string())?;
+    let (mapping, file) = &mut *guard;
+    if let Some(pseudonym) = mapping.get(patient_id) {
+        return Ok(pseudonym.clone());
+    }
+
+    let entry = MappingEntry {
+        original: patient_id.to_string(),
+        pseudonym: Uuid::new_v4().to_string(),
+    };
+    let line = serde_json::to_string(&entry).map_err(|err| err.to_string())?;
+    // Persist new pseudonym before using it
+    writeln!(file, "{line}")
+        .and_then(|()| file.sync_data())
+        .map_err(|err| format!("Cannot store pseudonym: {err}"))?;
+    mapping.insert(entry.original, entry.pseudonym.clone());
+    Ok(entry.pseudonym)
+}
+
+/// Uses the FHIR operation `$pseudonymizeAllowCreate` of gPAS
+pub struct GpasPseudonymizer {
+    url: String,
+    domain: String,
+    credentials: Option<(String, Option<SecretValue>)>,
+    client: reqwest::Client,
+}
+
+impl GpasPseudonymizer {
+    pub fn new(url: &str, domain: &str) -> Self {
+        Self {
+            url: url.to_string(),
+            domain: domain.to_string(),
+            credentials: None,
+            client: reqwest::Client::builder()
+                .connect_timeout(GPAS_CONNECT_TIMEOUT)
+                .timeout(GPAS_REQUEST_TIMEOUT)
+                .build()
+                .unwrap_or_default(),
+        }
+    }
+
+    pub fn with_basic_auth(mut self, username: &str, password: Option<SecretValue>) -> Self {
+        self.credentials = Some((username.to_string(), password));
+        self
+    }
+}
+
+/// Returns the pseudonym contained in gPAS response parameters
+fn gpas_pseudonym(parameters: &Value) -> Option<String> {
+    parameters["parameter"]
+        .as_array()?
+        .iter()
+        .filter(|parameter| parameter["name"] == "pseudonym")
+        .flat_map(|parameter| parameter["part"].as_array().into_iter().flatten())
+        .find(|part| part["name"] == "pseudonym")?["valueIdentifier"]["value"]
+        .as_str()
+        .map(ToString::to_string)
+}
+
+#[async_trait]
+impl Pseudonymizer for GpasPseudonymizer {
+    async fn pseudonymize(&self, patient_id: &str) -> Result<String, String> {
+        let parameters = json!({
+            "resourceType": "Parameters",
+            "parameter": [
+                { "name": "target", "valueString": self.domain },
+                { "name": "original", "valueString": patient_id }
+            ]
+        });
+
+        let mut request = self
+            .client
+            .post(&self.url)
+            .header("Content-Type", "application/fhir+json")
+            .json(&parameters);
+        if let Some((username, password)) = &self.credentials {
+            let password = password.as_ref().and_then(SecretValue::get);
+            request = request.basic_auth(username, password.as_deref());
+        }
+
+        let response = request
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(|err| format!("Cannot request gPAS: {err}"))?
+            .json::<Value>()
+            .await
+            .map_err(|err| format!("Invalid gPAS response: {err}"))?;
+
+        gpas_pseudonym(&response).ok_or_else(|| "No pseudonym in gPAS response".to_string())
+    }
+}
+
+/// Collects IDs of the patient and all references to patients
+fn collect_patient_ids(value: &Value, ids: &mut BTreeSet<String>) {
+    match value {
+        Value::Object(fields) => {
+            for (key, value) in fields {
+                if key == "patient"
+                    && let Some(id) = value.get("id").and_then(Value::as_str)
+                {
+                    ids.insert(id.to_string());
+                }
+                collect_patient_ids(value, ids);
+            }
+        }
+        Value::Array(items) => items.iter().for_each(|item| collect_patient_ids(item, ids)),
+        _ => {}
+    }
+}
+
+fn replace_patient_ids(value: &mut Value, pseudonyms: &HashMap<String, String>) {
+    match value {
+        Value::Object(fields) => {
+            for (key, value) in fields.iter_mut() {
+                if key == "patient"
+                    && let Some(id) = value.get_mut("id")
+                    && let Some(pseudonym) = id.as_str().and_then(|id| pseudonyms.get(id))
+                {
+                    *id = Value::String(pseudonym.clone());
+                }
+                replace_patient_ids(value, pseudonyms);
+            }
+        }
+        Value::Array(items) => items
+            .iter_mut()
+            .for_each(|item| replace_patient_ids(item, pseudonyms)),
+        _ => {}
+    }
+}
+
+/// Replaces the patient ID and all references to patients within the MTB file
+pub async fn pseudonymize_mtb(
+    mtb: Mtb,
+    pseudonymizer: &(dyn Pseudonymizer + Send + Sync),
+) -> Result<Mtb, String> {
+    let mut value = serde_json::to_value(mtb).map_err(|err| err.to_string())?;
+
+    let mut ids = BTreeSet::new();
+    collect_patient_ids(&value, &mut ids);
+
+    let mut pseudonyms = HashMap::new();
+    for id in ids {
+        let pseudonym = pseudonymizer.pseudonymize(&id).await?;
+        pseudonyms.insert(id, pseudonym);
+    }
+    replace_patient_ids(&mut value, &pseudonyms);
+
+    serde_json::from_value(value).map_err(|err| err.to_string())
+}
+
+/// Pseudonymizes MTB files before sending them using the wrapped sender
+pub struct PseudonymizingMtbFileSender {
+    pseudonymizer: DynPseudonymizer,
+    sender: DynMtbFileSender,
+}
+
+impl PseudonymizingMtbFileSender {
+    pub fn new(pseudonymizer: DynPseudonymizer, sender: DynMtbFileSender) -> Self {
+        Self {
+            pseudonymizer,
+            sender,
+        }
+    }
+}
+
+#[async_trait]
+impl MtbFileSender for PseudonymizingMtbFileSender {
+    async fn send(&self, mtb: Mtb, options: SendOptions) -> Result<SendReport, ()> {
+        let mtb = pseudonymize_mtb(mtb, self.pseudonymizer.as_ref())
+            .await
+            .map_err(|err| log::error!("Cannot pseudonymize MTB file: {err}"))?;
+        self.sender.send(mtb, options).await
+    }
+
+    async fn send_batch(&self, batch: Vec<(Mtb, SendOptions)>) -> Result<Vec<SendReport>, ()> {
+        let mut pseudonymized = vec![];
+        for (mtb, options) in batch {
+            let mtb = pseudonymize_mtb(mtb, self.pseudonymizer.as_ref())
+                .await
+                .map_err(|err| log::error!("Cannot pseudonymize MTB file: {err}"))?;
+            pseudonymized.push((mtb, options));
+        }
+        self.sender.send_batch(pseudonymized).await
+    }
+
+    fn transactional(&self) -> bool {
+        self.sender.transactional()
+    }
+
+    fn max_batch_size(&self) -> Option<usize> {
+        self.sender.max_batch_size()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pseudonym::{
+        GpasPseudonymizer, HmacPseudonymizer, MappingPseudonymizer, MockPseudonymizer,
+        Pseudonymizer, PseudonymizingMtbFileSender, pseudonymize_mtb,
+    };
+    use crate::sender::{DynMtbFileSender, MockMtbFileSender, SendOptions, SendReport};
+    use axum::routing::post;
+    use axum::{Json, Router};
+    use mv64e_mtb_dto::Mtb;
+    use serde_json::{Value, json};
+    use std::sync::Arc;
+
+    const PATIENT_ID: &str = "fae56ea7-24a7-4556-82fb-2b5dde71bb4d";
+
+    #[allow(clippy::expect_used)]
+    fn mtb() -> Mtb {
+        serde_json::from_str(include_str!("../test-files/mv64e-mtb-fake-patient.json"))
+            .expect("valid MTB file")
+    }
+
+    fn mock_pseudonymizer() -> MockPseudonymizer {
+        let mut pseudonymizer = MockPseudonymizer::new();
+        pseudonymizer
+            .expect_pseudonymize()
+            .returning(|patient_id| Ok(format!("PSN-{patient_id}")));
+        pseudonymizer
+    }
+
+    #[tokio::test]
+    async fn should_create_hmac_pseudonym() {
+        let pseudonymizer = HmacPseudonymizer::new(b"very-secret");
+        assert_eq!(
+            pseudonymizer.pseudonymize("P1").await,
+            Ok("30c60964c5d05e54ceb0300a115b4237dd1e1748b488c8b8cbf9d043f24f9a70".to_string())
+        );
+        assert_ne!(
+            HmacPseudonymizer::new(b"other-secret")
+                .pseudonymize("P1")
+                .await,
+            pseudonymizer.pseudonymize("P1").await
+        );
+    }
+
+    #[tokio::test]
+    #[allow(clippy::expect_used)]
+    async fn should_keep_mapped_pseudonyms() {
+        let dir = tempfile::tempdir().expect("temp dir");
+        let path = dir.path().join("pseudonyms.jsonl");
+
+        let pseudonymizer = MappingPseudonymizer::open(&path).expect("mapping opened");
+        let pseudonym = pseudonymizer.pseudonymize("P1").await.expect("pseudonym");
+        assert_eq!(
+            pseudonymizer.pseudonymize("P1").await,
+            Ok(pseudonym.clone())
+        );
+        assert_ne!(
+            pseudonymizer.pseudonymize("P2").await,
+            Ok(pseudonym.clone())
+        );
+        drop(pseudonymizer);
+
+        let pseudonymizer = MappingPseudonymizer::open(&path).expect("mapping reopened");
+        assert_eq!(pseudonymizer.pseudonymize("P1").await, Ok(pseudonym));
+    }
+
+    #[tokio::test]
+    #[allow(clippy::expect_used)]
+    async fn should_request_gpas_pseudonym() {
+        let app = Router::new().route(
+            "/ttp-fhir/fhir/gpas/$pseudonymizeAllowCreate",
+            post(|Json(parameters): Json<Value>| async move {
+                assert_eq!(parameters["parameter"][0]["valueString"], "MTB");
+                let original = parameters["parameter"][1]["valueString"].clone();
+                Json(json!({
+                    "resourceType": "Parameters",
+                    "parameter": [{
+                        "name": "pseudonym",
+                        "part": [
+                            { "name": "original", "valueIdentifier": { "value": original } },
+                            { "name": "target", "valueIdentifier": { "value": "MTB" } },
+                            { "name": "pseudonym", "valueIdentifier": { "value": "PSN-P1" } }
+                        ]
+                    }]
+                }))
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("listener bound");
+        let url = format!(
+            "http://{}/ttp-fhir/fhir/gpas/$pseudonymizeAllowCreate",
+            listener.local_addr().expect("local address")
+        );
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let pseudonymizer = GpasPseudonymizer::new(&url, "MTB").with_basic_auth("gpas", None);
+        assert_eq!(
+            pseudonymizer.pseudonymize("P1").await,
+            Ok("PSN-P1".to_string())
+        );
+    }
+
+    #[tokio::test]
+    #[allow(clippy::expect_used)]
+    async fn should_replace_all_patient_references() {
+        let mtb = pseudonymize_mtb(mtb(), &mock_pseudonymizer())
+            .await
+            .expect("pseudonymized");
+
+        assert_eq!(mtb.patient.id, format!("PSN-{PATIENT_ID}"));
+        let json = serde_json::to_string(&mtb).expect("serialized");
+        assert!(!json.contains(&format!("\"{PATIENT_ID}\"")));
+    }
+
+    #[tokio::test]
+    #[allow(clippy::expect_used)]
+    async fn should_send_pseudonymized_mtb_file() {
+        let mut sender_mock = MockMtbFileSender::new();
+        sender_mock
+            .expect_send()
+            .withf(|mtb, _| mtb.patient.id == "PSN-P1")
+            .return_once(|_, _| Ok(SendReport::accepted("")));
+
+        let sender = PseudonymizingMtbFileSender::new(
+            Arc::new(mock_pseudonymizer()),
+            Arc::new(sender_mock) as DynMtbFileSender,
+        );
+        let delete_mtb_file = Mtb::new_with_consent_rejected("P1");
+
+        assert!(
+            crate::sender::MtbFileSender::send(&sender, delete_mtb_file, SendOptions::default())
+                .await
+                .is_ok()
+        );
+    }
+
+    #[tokio::test]
+    async fn should_not_send_if_pseudonymization_fails() {
+        let mut pseudonymizer = MockPseudonymizer::new();
+        pseudonymizer
+            .expect_pseudonymize()
+            .returning(|_| Err("gPAS not available".to_string()));
+        let mut sender_mock = MockMtbFileSender::new();
+        sender_mock.expect_send().never();
+
+        let sender = PseudonymizingMtbFileSender::new(
+            Arc::new(pseudonymizer),
+            Arc::new(sender_mock) as DynMtbFileSender,
+        );
+
+        assert!(
+            crate::sender::MtbFileSender::send(
+                &sender,
+                Mtb::new_with_consent_rejected("P1"),
+                SendOptions::default()
+            )
+            .await
+            .is_err()
+        );
+    }
+}
@@ -0,0 +1,145 @@
+use axum::http::{HeaderMap, HeaderName};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::expiring::ExpiringMap;
+use crate::problem::Problem;
+
+pub const REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+const MAX_ENTRIES: usize = 100_000;
+
+/// Returns the request ID given by the `X-Request-Id` header as lowercase hyphenated UUID
+pub fn request_id(headers: &HeaderMap) -> Result<Option<String>, String> {
+    let Some(value) = headers.get(REQUEST_ID) else {
+        return Ok(None);
+    };
+    value
+        .to_str()
+        .ok()
+        .and_then(|value| Uuid::try_parse(value).ok())
+        .map(|uuid| Some(uuid.hyphenated().to_string()))
+        .ok_or_else(|| "The request ID must be a UUID".to_string())
+}
+
+/// Uses the request ID given by the client, if valid, for the rejection of the request
+pub fn with_client_request_id(problem: Problem, headers: &HeaderMap) -> Problem {
+    match request_id(headers) {
+        Ok(Some(request_id)) => problem.with_request_id(&request_id),
+        _ => problem,
+    }
+}
+
+/// Request IDs given by clients, retained for the configured TTL to reject reusing them.
+/// At most 100,000 request IDs are retained.
+pub struct RequestIdStore {
+    request_ids: Mutex<ExpiringMap<()>>,
+}
+
+impl RequestIdStore {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            request_ids: Mutex::new(ExpiringMap::new(ttl, MAX_ENTRIES)),
+        }
+    }
+
+    /// Claims the request ID, returns `false` if already used
+    pub fn claim(&self, request_id: &str) -> bool {
+        let Ok(mut request_ids) = self.request_ids.lock() else {
+            return true;
+        };
+        if request_ids.get(request_id).is_some() {
+            return false;
+        }
+        request_ids.insert(request_id.to_string(), ());
+        true
+    }
+
+    /// Releases the request ID of a failed request, allowing to retry it
+    fn release(&self, request_id: &str) {
+        if let Ok(mut request_ids) = self.request_ids.lock() {
+            request_ids.remove(request_id);
+        }
+    }
+}
+
+/// Claimed request ID. Unless kept, the request ID is released when dropped, e.g. if sending
+/// fails or the client disconnects before the MTB file has been sent, allowing to retry the request.
+pub struct RequestIdGuard {
+    store: Arc<RequestIdStore>,
+    request_id: Option<String>,
+}
+
+impl RequestIdGuard {
+    pub fn new(store: Arc<RequestIdStore>, request_id: String) -> Self {
+        Self {
+            store,
+            request_id: Some(request_id),
+        }
+    }
+
+    /// Keeps the request ID of the accepted MTB file claimed
+    pub fn keep(mut self) {
+        self.request_id = None;
+    }
+}
+
+impl Drop for RequestIdGuard {
+    fn drop(&mut self) {
+        if let Some(request_id) = self.request_id.take() {
+            self.store.release(&request_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::request_id::{REQUEST_ID, RequestIdGuard, RequestIdStore, request_id};
+    use axum::http::{HeaderMap, HeaderValue};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn should_return_normalized_request_id() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(request_id(&headers), Ok(None));
+
+        headers.insert(
+            REQUEST_ID,
+            HeaderValue::from_static("B9AC5A60A8E64C0FBD0B6D3E7BB6E1E7"),
+        );
+        assert_eq!(
+            request_id(&headers),
+            Ok(Some("b9ac5a60-a8e6-4c0f-bd0b-6d3e7bb6e1e7".to_string()))
+        );
+
+        headers.insert(REQUEST_ID, HeaderValue::from_static("export-42"));
+        assert!(request_id(&headers).is_err());
+    }
+
+    #[test]
+    fn should_reject_reused_request_ids() {
+        let store = RequestIdStore::new(Duration::from_mins(1));
+        assert!(store.claim("id"));
+        assert!(!store.claim("id"));
+        store.release("id");
+        assert!(store.claim("id"));
+
+        let store = RequestIdStore::new(Duration::ZERO);
+        assert!(store.claim("id"));
+        assert!(store.claim("id"));
+    }
+
+    #[test]
+    fn should_release_request_id_unless_kept() {
+        let store = Arc::new(RequestIdStore::new(Duration::from_mins(1)));
+
+        assert!(store.claim("id"));
+        drop(RequestIdGuard::new(Arc::clone(&store), "id".to_string()));
+        assert!(store.claim("id"));
+
+        RequestIdGuard::new(Arc::clone(&store), "id".to_string()).keep();
+        assert!(!store.claim("id"));
+    }
+}
@@ -0,0 +1,242 @@
+use axum::http::{HeaderMap, HeaderName};
+use mv64e_mtb_dto::Mtb;
+use serde::{Deserialize, Serialize};
+
+/// Rule selecting the topic of matching MTB files. All given conditions must match.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RoutingRule {
+    pub topic: String,
+    /// Name of the authenticated user
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    /// Name of a request header that must be present
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub header: Option<String>,
+    /// Required value of the request header
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub header_value: Option<String>,
+    /// Whether the MTB file must contain metadata or not, e.g. `false` for deletions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<bool>,
+    /// Prefix of the ICD-10 code of any diagnosis, e.g. `C50`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub icd10: Option<String>,
+}
+
+impl RoutingRule {
+    fn validate(&self) -> Result<(), String> {
+        if self.topic.trim().is_empty() {
+            return Err("Routing rule without topic".to_string());
+        }
+        if self.user.is_none()
+            && self.header.is_none()
+            && self.metadata.is_none()
+            && self.icd10.is_none()
+        {
+            return Err(format!(
+                "Routing rule for topic '{}' has no conditions",
+                self.topic
+            ));
+        }
+        match (&self.header, &self.header_value) {
+            (Some(header), _) if HeaderName::from_bytes(header.as_bytes()).is_err() => {
+                Err(format!(
+                    "Routing rule for topic '{}' has an invalid header",
+                    self.topic
+                ))
+            }
+            (None, Some(_)) => Err(format!(
+                "Routing rule for topic '{}' has a header value but no header",
+                self.topic
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn matches(&self, mtb: &Mtb, username: Option<&str>, headers: &HeaderMap) -> bool {
+        self.user
+            .as_deref()
+            .is_none_or(|user| username == Some(user))
+            && self.header.as_deref().is_none_or(|header| {
+                headers.get_all(header).iter().any(|value| {
+                    self.header_value
+                        .as_deref()
+                        .is_none_or(|expected| value.as_bytes() == expected.as_bytes())
+                })
+            })
+            && self
+                .metadata
+                .is_none_or(|metadata| metadata == mtb.metadata.is_some())
+            && self.icd10.as_deref().is_none_or(|prefix| {
+                icd10_codes(mtb).iter().any(|code| {
+                    code.get(..prefix.len())
+                        .is_some_and(|start| start.eq_ignore_ascii_case(prefix))
+                })
+            })
+    }
+}
+
+/// Selects the topic using the first matching rule or the default topic
+pub struct TopicRouting {
+    default_topic: String,
+    rules: Vec<RoutingRule>,
+}
+
+impl TopicRouting {
+    pub fn new(default_topic: &str, rules: Vec<RoutingRule>) -> Result<Self, String> {
+        for rule in &rules {
+            rule.validate()?;
+        }
+        Ok(Self {
+            default_topic: default_topic.to_string(),
+            rules,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub fn topic(&self, mtb: &Mtb, username: Option<&str>, headers: &HeaderMap) -> &str {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(mtb, username, headers))
+            .map_or(&self.default_topic, |rule| &rule.topic)
+    }
+}
+
+/// ICD-10 codes of all diagnoses
+fn icd10_codes(mtb: &Mtb) -> Vec<String> {
+    let Ok(diagnoses) = serde_json::to_value(&mtb.diagnoses) else {
+        return vec![];
+    };
+    diagnoses
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|diagnosis| diagnosis["code"]["code"].as_str())
+        .map(ToString::to_string)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::routing::{RoutingRule, TopicRouting};
+    use axum::http::{HeaderMap, HeaderValue};
+    use mv64e_mtb_dto::Mtb;
+
+    #[allow(clippy::expect_used)]
+    fn mtb() -> Mtb {
+        serde_json::from_str(include_str!("../test-files/mv64e-mtb-fake-patient.json"))
+            .expect("valid MTB file")
+    }
+
+    fn rule(topic: &str) -> RoutingRule {
+        RoutingRule {
+            topic: topic.to_string(),
+            ..RoutingRule::default()
+        }
+    }
+
+    #[test]
+    #[allow(clippy::expect_used)]
+    fn should_route_by_first_matching_rule() {
+        let routing = TopicRouting::new(
+            "default-topic",
+            vec![
+                RoutingRule {
+                    user: Some("site-a".to_string()),
+                    ..rule("site-a")
+                },
+                RoutingRule {
+                    header: Some("X-Dnpm-Project".to_string()),
+                    header_value: Some("study-1".to_string()),
+                    ..rule("project")
+                },
+                RoutingRule {
+                    icd10: Some("c71".to_string()),
+                    ..rule("neuro-oncology")
+                },
+                RoutingRule {
+                    icd10: Some("C72".to_string()),
+                    ..rule("cranial-nerves")
+                },
+                RoutingRule {
+                    metadata: Some(false),
+                    ..rule("deletions")
+                },
+            ],
+        )
+        .expect("valid rules");
+        let mut headers = HeaderMap::new();
+        let mtb = mtb();
+
+        assert_eq!(
+            routing.topic(&mtb, Some("site-b"), &headers),
+            "cranial-nerves"
+        );
+        assert_eq!(routing.topic(&mtb, Some("site-a"), &headers), "site-a");
+        assert_eq!(
+            routing.topic(
+                &Mtb::new_with_consent_rejected("P1"),
+                Some("site-b"),
+                &headers
+            ),
+            "deletions"
+        );
+
+        headers.insert("x-dnpm-project", HeaderValue::from_static("study-2"));
+        assert_eq!(routing.topic(&mtb, None, &headers), "cranial-nerves");
+        headers.insert("x-dnpm-project", HeaderValue::from_static("study-1"));
+        assert_eq!(routing.topic(&mtb, None, &headers), "project");
+    }
+
+    #[test]
+    #[allow(clippy::expect_used)]
+    fn should_use_default_topic() {
+        let routing = TopicRouting::new(
+            "default-topic",
+            vec![
+                RoutingRule {
+                    icd10: Some("C72".to_string()),
+                    ..rule("cranial-nerves")
+                },
+                RoutingRule {
+                    metadata: Some(true),
+                    ..rule("submissions")
+                },
+            ],
+        )
+        .expect("valid rules");
+        let mut json: serde_json::Value =
+            serde_json::from_str(include_str!("../test-files/mv64e-mtb-fake-patient.json"))
+                .expect("valid JSON");
+        json["diagnoses"][0]["code"]["code"] = "C50.1".into();
+        let mtb = serde_json::from_value(json).expect("valid MTB file");
+
+        assert_eq!(
+            routing.topic(&mtb, Some("site-b"), &HeaderMap::new()),
+            "default-topic"
+        );
+    }
+
+    #[test]
+    fn should_reject_invalid_rules() {
+        let rule = |header: Option<&str>, header_value: Option<&str>| RoutingRule {
+            topic: "topic".to_string(),
+            header: header.map(ToString::to_string),
+            header_value: header_value.map(ToString::to_string),
+            ..RoutingRule::default()
+        };
+
+        assert!(TopicRouting::new("default-topic", vec![rule(None, None)]).is_err());
+        assert!(TopicRouting::new("default-topic", vec![rule(None, Some("value"))]).is_err());
+        assert!(TopicRouting::new("default-topic", vec![rule(Some("in valid"), None)]).is_err());
+        assert!(TopicRouting::new("default-topic", vec![rule(Some("X-Project"), None)]).is_ok());
+    }
+}
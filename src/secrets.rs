@@ -0,0 +1,212 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::sync::{LazyLock, RwLock};
+use zeroize::Zeroizing;
+
+use crate::CONFIG;
+
+/// Secrets configured by value or file. Reloaded on SIGHUP.
+pub static SECRETS: LazyLock<SecretStore> =
+    LazyLock::new(|| SecretStore::new(load_secrets().unwrap_or_default()));
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Secret {
+    Token,
+    SslKeyPassword,
+    SaslPassword,
+    SaslOauthClient,
+    PseudonymHmacKey,
+    GpasPassword,
+}
+
+impl Secret {
+    const ALL: [Secret; 6] = [
+        Secret::Token,
+        Secret::SslKeyPassword,
+        Secret::SaslPassword,
+        Secret::SaslOauthClient,
+        Secret::PseudonymHmacKey,
+        Secret::GpasPassword,
+    ];
+
+    /// Configured value and file of the secret
+    fn configured(self) -> (Option<&'static str>, Option<&'static str>) {
+        match self {
+            Secret::Token => (CONFIG.token.as_deref(), CONFIG.token_file.as_deref()),
+            Secret::SslKeyPassword => (
+                CONFIG.ssl_key_password.as_deref(),
+                CONFIG.ssl_key_password_file.as_deref(),
+            ),
+            Secret::SaslPassword => (
+                CONFIG.sasl_password.as_deref(),
+                CONFIG.sasl_password_file.as_deref(),
+            ),
+            Secret::SaslOauthClient => (
+                CONFIG.sasl_oauth_client_secret.as_deref(),
+                CONFIG.sasl_oauth_client_secret_file.as_deref(),
+            ),
+            Secret::PseudonymHmacKey => (
+                CONFIG.pseudonym_hmac_key.as_deref(),
+                CONFIG.pseudonym_hmac_key_file.as_deref(),
+            ),
+            Secret::GpasPassword => (
+                CONFIG.gpas_password.as_deref(),
+                CONFIG.gpas_password_file.as_deref(),
+            ),
+        }
+    }
+
+    /// Whether a replaced secret is used without restarting the application
+    pub fn is_reloadable(self) -> bool {
+        // Kafka clients keep their initial configuration and pseudonyms must not change
+        // while running
+        !matches!(
+            self,
+            Secret::SslKeyPassword | Secret::SaslPassword | Secret::PseudonymHmacKey
+        )
+    }
+}
+
+impl Display for Secret {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Secret::Token => "security token",
+            Secret::SslKeyPassword => "Kafka SSL key password",
+            Secret::SaslPassword => "Kafka SASL password",
+            Secret::SaslOauthClient => "Kafka SASL OAuth client secret",
+            Secret::PseudonymHmacKey => "pseudonym HMAC key",
+            Secret::GpasPassword => "gPAS password",
+        })
+    }
+}
+
+/// Secret given as value or looked up in the secret store whenever used
+#[derive(Clone)]
+pub enum SecretValue {
+    Fixed(Zeroizing<String>),
+    Configured(Secret),
+}
+
+impl SecretValue {
+    pub fn get(&self) -> Option<Zeroizing<String>> {
+        match self {
+            SecretValue::Fixed(value) => Some(value.clone()),
+            SecretValue::Configured(secret) => SECRETS.get(*secret),
+        }
+    }
+}
+
+impl From<&str> for SecretValue {
+    fn from(value: &str) -> Self {
+        SecretValue::Fixed(Zeroizing::new(value.to_string()))
+    }
+}
+
+/// Secrets replaceable at runtime. Replaced values are zeroized.
+pub struct SecretStore(RwLock<HashMap<Secret, Zeroizing<String>>>);
+
+impl SecretStore {
+    pub fn new(secrets: HashMap<Secret, Zeroizing<String>>) -> Self {
+        Self(RwLock::new(secrets))
+    }
+
+    pub fn get(&self, secret: Secret) -> Option<Zeroizing<String>> {
+        self.0.read().ok()?.get(&secret).cloned()
+    }
+
+    /// Replaces all secrets and returns the changed ones
+    pub fn replace(&self, secrets: HashMap<Secret, Zeroizing<String>>) -> Vec<Secret> {
+        let Ok(mut current) = self.0.write() else {
+            return vec![];
+        };
+        let changed = Secret::ALL
+            .into_iter()
+            .filter(|secret| current.get(secret) != secrets.get(secret))
+            .collect();
+        *current = secrets;
+        changed
+    }
+
+    /// Reloads secrets as configured and returns the changed ones
+    pub fn reload(&self) -> Result<Vec<Secret>, String> {
+        Ok(self.replace(load_secrets()?))
+    }
+}
+
+/// Returns the secret read from file if given, otherwise the secret value
+pub fn read_secret(
+    value: Option<&str>,
+    file: Option<&str>,
+) -> Result<Option<Zeroizing<String>>, String> {
+    match file {
+        Some(file) => fs::read_to_string(file)
+            .map(|content| {
+                let content = Zeroizing::new(content);
+                Some(Zeroizing::new(
+                    content.trim_end_matches(['\r', '\n']).to_string(),
+                ))
+            })
+            .map_err(|err| format!("Cannot read secret file '{file}': {err}")),
+        None => Ok(value.map(|value| Zeroizing::new(value.to_string()))),
+    }
+}
+
+fn load_secrets() -> Result<HashMap<Secret, Zeroizing<String>>, String> {
+    let mut secrets = HashMap::new();
+    for secret in Secret::ALL {
+        let (value, file) = secret.configured();
+        if let Some(value) = read_secret(value, file)? {
+            secrets.insert(secret, value);
+        }
+    }
+    Ok(secrets)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::secrets::{Secret, SecretStore, read_secret};
+    use std::collections::HashMap;
+    use std::io::Write;
+    use zeroize::Zeroizing;
+
+    #[test]
+    #[allow(clippy::expect_used)]
+    fn should_read_secret_from_file() {
+        let mut file = tempfile::NamedTempFile::new().expect("temp file");
+        writeln!(file, "very-secret").expect("secret written");
+        let file = file.path().to_str().expect("valid path");
+
+        assert_eq!(
+            read_secret(Some("ignored"), Some(file)).map(|secret| secret.as_deref().cloned()),
+            Ok(Some("very-secret".to_string()))
+        );
+        assert_eq!(
+            read_secret(Some("secret"), None).map(|secret| secret.as_deref().cloned()),
+            Ok(Some("secret".to_string()))
+        );
+        assert!(read_secret(None, Some("/nonexistent/secret")).is_err());
+    }
+
+    #[test]
+    fn should_replace_secrets() {
+        let secret = |value: &str| Zeroizing::new(value.to_string());
+        let store = SecretStore::new(HashMap::from([
+            (Secret::Token, secret("token")),
+            (Secret::GpasPassword, secret("password")),
+        ]));
+
+        let changed = store.replace(HashMap::from([
+            (Secret::Token, secret("token")),
+            (Secret::GpasPassword, secret("new-password")),
+            (Secret::SaslPassword, secret("sasl-password")),
+        ]));
+
+        assert_eq!(changed, vec![Secret::SaslPassword, Secret::GpasPassword]);
+        assert_eq!(
+            store.get(Secret::GpasPassword),
+            Some(secret("new-password"))
+        );
+        assert_eq!(store.get(Secret::SslKeyPassword), None);
+    }
+}
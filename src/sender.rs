@@ -1,77 +1,557 @@
 use async_trait::async_trait;
+use futures_util::future::join_all;
 use mv64e_mtb_dto::Mtb;
+use rdkafka::error::{KafkaError, KafkaResult, RDKafkaErrorCode};
 use rdkafka::message::{Header, OwnedHeaders};
-use rdkafka::producer::{FutureProducer, FutureRecord};
+use rdkafka::producer::{FutureProducer, FutureRecord, Producer};
+use serde::{Deserialize, Serialize};
 use std::sync::Arc;
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tracing::Instrument;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
 use uuid::Uuid;
 
 #[cfg(test)]
 use mockall::automock;
 
 use crate::RecordKey;
+use crate::kafka::KafkaContext;
+use crate::metrics::METRICS;
+use crate::telemetry::TraceContext;
 
 pub type DynMtbFileSender = Arc<dyn MtbFileSender + Send + Sync>;
 
+/// Timeout of initializing, committing or aborting transactions
+pub const TRANSACTION_TIMEOUT: Duration = Duration::from_secs(30);
+
+const COMMIT_ATTEMPTS: usize = 3;
+
 #[cfg_attr(test, automock)]
 #[async_trait]
 pub trait MtbFileSender {
-    async fn send(&self, mtb: Mtb) -> Result<String, ()>;
+    /// Sends the MTB file. If `wait_for_delivery` is set in the options, the returned report
+    /// should contain the Kafka record the MTB file became.
+    async fn send(&self, mtb: Mtb, options: SendOptions) -> Result<SendReport, ()>;
+
+    /// Sends all MTB files of a batch. If the sender is transactional, either all or none of
+    /// the MTB files become visible to consumers.
+    async fn send_batch(&self, batch: Vec<(Mtb, SendOptions)>) -> Result<Vec<SendReport>, ()> {
+        let mut reports = vec![];
+        for (mtb, options) in batch {
+            reports.push(self.send(mtb, options).await?);
+        }
+        Ok(reports)
+    }
+
+    /// Whether batches are sent atomically
+    fn transactional(&self) -> bool {
+        false
+    }
+
+    /// Maximum number of MTB files of a batch, not limited if `None`
+    fn max_batch_size(&self) -> Option<usize> {
+        None
+    }
+}
+
+/// Options of a single request to send an MTB file
+#[derive(Clone, Debug, Default)]
+pub struct SendOptions {
+    pub wait_for_delivery: bool,
+    pub username: Option<String>,
+    pub warnings: Vec<String>,
+    /// Topic selected by routing rules instead of the default topic
+    pub topic: Option<String>,
+    pub trace: TraceContext,
+    /// Request ID given by the client instead of a generated one
+    pub request_id: Option<String>,
+    /// Allowed HTTP headers with source metadata
+    pub source_headers: Vec<(String, String)>,
+}
+
+/// Kafka record a sent MTB file became
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Delivery {
+    pub topic: String,
+    pub partition: i32,
+    pub offset: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<i64>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SendReport {
+    pub request_id: String,
+    #[serde(flatten)]
+    pub delivery: Option<Delivery>,
+    /// MTB file not sent again since unchanged since the request with this ID
+    #[serde(skip)]
+    pub unchanged: bool,
+}
+
+impl SendReport {
+    pub fn accepted(request_id: &str) -> Self {
+        Self {
+            request_id: request_id.to_string(),
+            delivery: None,
+            unchanged: false,
+        }
+    }
+
+    pub fn unchanged(request_id: &str) -> Self {
+        Self {
+            request_id: request_id.to_string(),
+            delivery: None,
+            unchanged: true,
+        }
+    }
+}
+
+/// A serialized MTB file ready to be sent to Kafka
+#[derive(Serialize, Deserialize)]
+pub struct MtbRecord {
+    pub request_id: String,
+    pub key: String,
+    pub payload: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub topic: Option<String>,
+    #[serde(default, skip_serializing_if = "TraceContext::is_empty")]
+    pub trace: TraceContext,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub source_headers: Vec<(String, String)>,
+}
+
+impl MtbRecord {
+    pub fn new(mtb: &Mtb, options: &SendOptions) -> Result<Self, ()> {
+        let record_key = RecordKey {
+            patient_id: mtb.patient.id.to_string(),
+        };
+
+        Ok(Self {
+            request_id: options
+                .request_id
+                .clone()
+                .unwrap_or_else(|| Uuid::new_v4().to_string()),
+            key: serde_json::to_string(&record_key).map_err(|_| ())?,
+            payload: serde_json::to_string(mtb).map_err(|_| ())?,
+            username: options.username.clone(),
+            warnings: options.warnings.clone(),
+            topic: options.topic.clone(),
+            trace: options.trace.clone(),
+            source_headers: options.source_headers.clone(),
+        })
+    }
 }
 
 #[allow(clippy::module_name_repetitions)]
 #[derive(Clone)]
 pub struct DefaultMtbFileSender {
     topic: String,
-    producer: FutureProducer,
+    producer: FutureProducer<KafkaContext>,
 }
 
 impl DefaultMtbFileSender {
-    pub fn new(topic: &str, producer: FutureProducer) -> Self {
+    pub fn new(topic: &str, producer: FutureProducer<KafkaContext>) -> Self {
         Self {
             topic: topic.to_string(),
             producer,
         }
     }
-}
-
-#[async_trait]
-impl MtbFileSender for DefaultMtbFileSender {
-    async fn send(&self, mtb: Mtb) -> Result<String, ()> {
-        let request_id = Uuid::new_v4();
 
-        let record_key = RecordKey {
-            patient_id: mtb.patient.id.to_string(),
-        };
-
-        let record_headers = OwnedHeaders::default()
+    pub async fn send_record(&self, record: &MtbRecord) -> KafkaResult<Delivery> {
+        let mut record_headers = OwnedHeaders::default()
             .insert(Header {
                 key: "requestId",
-                value: Some(&request_id.to_string()),
+                value: Some(&record.request_id),
             })
             .insert(Header {
                 key: "contentType",
                 value: Some("application/vnd.dnpm.v2.mtb+json"),
             });
+        if let Some(username) = &record.username {
+            record_headers = record_headers.insert(Header {
+                key: "username",
+                value: Some(username),
+            });
+        }
+        let warnings = serde_json::to_string(&record.warnings)
+            .map_err(|_| KafkaError::MessageProduction(RDKafkaErrorCode::InvalidMessage))?;
+        if !record.warnings.is_empty() {
+            record_headers = record_headers.insert(Header {
+                key: "validationWarnings",
+                value: Some(&warnings),
+            });
+        }
 
-        let record_key = serde_json::to_string(&record_key).map_err(|_| ())?;
-
-        match serde_json::to_string(&mtb) {
-            Ok(json) => {
-                self.producer
-                    .send(
-                        FutureRecord::to(&self.topic)
-                            .key(&record_key)
-                            .headers(record_headers)
-                            .payload(&json),
-                        Duration::from_secs(1),
-                    )
-                    .await
-                    .map_err(|_| ())
-                    .map(|_| ())?;
-                Ok(request_id.to_string())
+        for (name, value) in &record.source_headers {
+            record_headers = record_headers.insert(Header {
+                key: name,
+                value: Some(value),
+            });
+        }
+
+        let topic = record.topic.as_deref().unwrap_or(&self.topic);
+        // Continues the trace of the request, even if sent later from the outbox
+        let span = tracing::info_span!(
+            "produce",
+            otel.name = format!("send {topic}"),
+            otel.kind = "producer",
+            messaging.system = "kafka",
+            messaging.operation.type = "send",
+            messaging.destination.name = topic,
+            request_id = record.request_id,
+        );
+        let _ = span.set_parent(record.trace.parent());
+        for (key, value) in record.trace.kafka_headers(&span) {
+            record_headers = record_headers.insert(Header {
+                key,
+                value: Some(&value),
+            });
+        }
+
+        let start = Instant::now();
+        let delivery = self
+            .producer
+            .send(
+                FutureRecord::to(topic)
+                    .key(&record.key)
+                    .headers(record_headers)
+                    .payload(&record.payload),
+                Duration::from_secs(1),
+            )
+            .instrument(span)
+            .await
+            .map_err(|(err, _)| err)?;
+        METRICS.observe_delivery(start.elapsed());
+
+        Ok(Delivery {
+            topic: topic.to_string(),
+            partition: delivery.partition,
+            offset: delivery.offset,
+            timestamp: delivery.timestamp.to_millis(),
+        })
+    }
+}
+
+/// Whether sending a record failed for a reason that does not change on retry, e.g. if the
+/// record is too large or the topic does not exist
+pub fn is_permanent(err: &KafkaError) -> bool {
+    matches!(
+        err,
+        KafkaError::MessageProduction(
+            RDKafkaErrorCode::MessageSizeTooLarge
+                | RDKafkaErrorCode::InvalidMessageSize
+                | RDKafkaErrorCode::InvalidMessage
+                | RDKafkaErrorCode::InvalidRecord
+                | RDKafkaErrorCode::MessageBatchTooLarge
+                | RDKafkaErrorCode::UnknownTopic
+                | RDKafkaErrorCode::UnknownTopicOrPartition
+                | RDKafkaErrorCode::InvalidTopic
+                | RDKafkaErrorCode::TopicAuthorizationFailed
+        )
+    )
+}
+
+#[async_trait]
+impl MtbFileSender for DefaultMtbFileSender {
+    async fn send(&self, mtb: Mtb, options: SendOptions) -> Result<SendReport, ()> {
+        let record = MtbRecord::new(&mtb, &options)?;
+        let delivery = self.send_record(&record).await.map_err(|_| ())?;
+        Ok(SendReport {
+            request_id: record.request_id,
+            delivery: Some(delivery),
+            unchanged: false,
+        })
+    }
+}
+
+/// Operation of a transaction, blocking until completed
+#[derive(Clone, Copy, Debug)]
+pub enum TransactionOperation {
+    Begin,
+    Commit,
+    Abort,
+}
+
+impl TransactionOperation {
+    fn name(self) -> &'static str {
+        match self {
+            TransactionOperation::Begin => "begin",
+            TransactionOperation::Commit => "commit",
+            TransactionOperation::Abort => "abort",
+        }
+    }
+}
+
+/// Producer configured with `transactional.id` with initialized transactions
+#[async_trait]
+pub trait TransactionalProducer {
+    async fn run(&self, operation: TransactionOperation) -> KafkaResult<()>;
+
+    async fn produce(&self, record: &MtbRecord) -> KafkaResult<Delivery>;
+}
+
+#[async_trait]
+impl TransactionalProducer for DefaultMtbFileSender {
+    async fn run(&self, operation: TransactionOperation) -> KafkaResult<()> {
+        let producer = self.producer.clone();
+        tokio::task::spawn_blocking(move || match operation {
+            TransactionOperation::Begin => producer.begin_transaction(),
+            TransactionOperation::Commit => commit_transaction(&producer),
+            TransactionOperation::Abort => producer.abort_transaction(TRANSACTION_TIMEOUT),
+        })
+        .await
+        .map_err(|_| KafkaError::Canceled)?
+    }
+
+    async fn produce(&self, record: &MtbRecord) -> KafkaResult<Delivery> {
+        self.send_record(record).await
+    }
+}
+
+/// Sends MTB files in transactions using a producer configured with `transactional.id`.
+/// Records of a transaction only become visible to consumers reading committed records after
+/// the transaction has been committed.
+#[allow(clippy::module_name_repetitions)]
+pub struct TransactionalMtbFileSender<P = DefaultMtbFileSender> {
+    transactions: Arc<Transactions<P>>,
+    max_transaction_size: usize,
+}
+
+impl<P> TransactionalMtbFileSender<P> {
+    /// Uses a producer with initialized transactions. The flag `failed` is set if the producer
+    /// failed and must be restarted.
+    pub fn new(producer: P, max_transaction_size: usize, failed: Arc<AtomicBool>) -> Self {
+        Self {
+            transactions: Arc::new(Transactions {
+                producer,
+                transaction: Mutex::new(()),
+                failed,
+            }),
+            max_transaction_size,
+        }
+    }
+}
+
+struct Transactions<P> {
+    producer: P,
+    // A producer supports only a single transaction at a time
+    transaction: Mutex<()>,
+    failed: Arc<AtomicBool>,
+}
+
+impl<P: TransactionalProducer> Transactions<P> {
+    async fn run(&self, operation: TransactionOperation) -> Result<(), ()> {
+        let action = operation.name();
+        match self.producer.run(operation).await {
+            Ok(()) => Ok(()),
+            Err(err) => {
+                if let KafkaError::Transaction(err) = &err
+                    && err.is_fatal()
+                {
+                    // Fatal errors, e.g. if fenced by another producer using the same
+                    // transactional ID, cannot be recovered from without a new producer
+                    self.failed.store(true, Ordering::Relaxed);
+                    log::error!(
+                        "Cannot {action} transaction, producer failed and must be restarted: {err}"
+                    );
+                } else {
+                    log::error!("Cannot {action} transaction: {err}");
+                }
+                Err(())
             }
-            Err(_) => Err(()),
         }
     }
+
+    async fn send_transaction(&self, records: &[MtbRecord]) -> Result<Vec<Delivery>, ()> {
+        let _transaction = self.transaction.lock().await;
+        if self.failed.load(Ordering::Relaxed) {
+            log::error!("Cannot send records using a failed transactional producer");
+            return Err(());
+        }
+
+        let committed = match self.run(TransactionOperation::Begin).await {
+            Ok(()) => self.produce_and_commit(records).await,
+            Err(()) => Err(()),
+        };
+        // Also aborts if beginning failed, e.g. since a previous transaction is still open
+        if committed.is_err() && !self.failed.load(Ordering::Relaxed) {
+            log::warn!("Aborting transaction of {} record(s)", records.len());
+            let _ = self.run(TransactionOperation::Abort).await;
+        }
+        committed
+    }
+
+    async fn produce_and_commit(&self, records: &[MtbRecord]) -> Result<Vec<Delivery>, ()> {
+        let deliveries = join_all(records.iter().map(|record| self.producer.produce(record)))
+            .await
+            .into_iter()
+            .collect::<KafkaResult<Vec<_>>>()
+            .map_err(|_| ())?;
+        self.run(TransactionOperation::Commit).await?;
+        Ok(deliveries)
+    }
+}
+
+/// Commits the transaction, retrying on retriable errors like timeouts
+fn commit_transaction(producer: &FutureProducer<KafkaContext>) -> KafkaResult<()> {
+    let mut result = producer.commit_transaction(TRANSACTION_TIMEOUT);
+    for _ in 1..COMMIT_ATTEMPTS {
+        match &result {
+            Err(KafkaError::Transaction(err)) if err.is_retriable() => {
+                result = producer.commit_transaction(TRANSACTION_TIMEOUT);
+            }
+            _ => break,
+        }
+    }
+    result
+}
+
+#[async_trait]
+impl<P> MtbFileSender for TransactionalMtbFileSender<P>
+where
+    P: TransactionalProducer + Send + Sync + 'static,
+{
+    async fn send(&self, mtb: Mtb, options: SendOptions) -> Result<SendReport, ()> {
+        let mut reports = self.send_batch(vec![(mtb, options)]).await?;
+        reports.pop().ok_or(())
+    }
+
+    async fn send_batch(&self, batch: Vec<(Mtb, SendOptions)>) -> Result<Vec<SendReport>, ()> {
+        let records = batch
+            .iter()
+            .map(|(mtb, options)| MtbRecord::new(mtb, options))
+            .collect::<Result<Vec<_>, ()>>()?;
+        let request_ids = records
+            .iter()
+            .map(|record| record.request_id.clone())
+            .collect::<Vec<_>>();
+        // Runs detached from the request, so the transaction is committed or aborted even if
+        // the client disconnects
+        let transactions = Arc::clone(&self.transactions);
+        let deliveries = tokio::spawn(async move { transactions.send_transaction(&records).await })
+            .await
+            .map_err(|_| ())??;
+        Ok(request_ids
+            .into_iter()
+            .zip(deliveries)
+            .map(|(request_id, delivery)| SendReport {
+                request_id,
+                delivery: Some(delivery),
+                unchanged: false,
+            })
+            .collect())
+    }
+
+    fn transactional(&self) -> bool {
+        true
+    }
+
+    fn max_batch_size(&self) -> Option<usize> {
+        Some(self.max_transaction_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sender::{
+        Delivery, MtbFileSender, MtbRecord, SendOptions, TransactionOperation,
+        TransactionalMtbFileSender, TransactionalProducer,
+    };
+    use async_trait::async_trait;
+    use mv64e_mtb_dto::Mtb;
+    use rdkafka::error::{KafkaError, KafkaResult};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[derive(Default)]
+    struct FakeProducer {
+        operations: Arc<Mutex<Vec<&'static str>>>,
+        fail_begin: AtomicBool,
+    }
+
+    #[async_trait]
+    impl TransactionalProducer for FakeProducer {
+        async fn run(&self, operation: TransactionOperation) -> KafkaResult<()> {
+            if let Ok(mut operations) = self.operations.lock() {
+                operations.push(operation.name());
+            }
+            if matches!(operation, TransactionOperation::Begin)
+                && self.fail_begin.swap(false, Ordering::Relaxed)
+            {
+                return Err(KafkaError::Canceled);
+            }
+            Ok(())
+        }
+
+        async fn produce(&self, record: &MtbRecord) -> KafkaResult<Delivery> {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            Ok(Delivery {
+                topic: record.topic.clone().unwrap_or_default(),
+                partition: 0,
+                offset: 0,
+                timestamp: None,
+            })
+        }
+    }
+
+    #[allow(clippy::expect_used)]
+    fn batch() -> Vec<(Mtb, SendOptions)> {
+        let mtb =
+            serde_json::from_str::<Mtb>(include_str!("../test-files/mv64e-mtb-fake-patient.json"))
+                .expect("MTB file");
+        vec![(mtb, SendOptions::default())]
+    }
+
+    fn operations(operations: &Mutex<Vec<&'static str>>) -> Vec<&'static str> {
+        operations
+            .lock()
+            .map(|operations| operations.clone())
+            .unwrap_or_default()
+    }
+
+    #[tokio::test]
+    async fn should_complete_transaction_if_request_is_dropped() {
+        let producer = FakeProducer::default();
+        let operations_sent = Arc::clone(&producer.operations);
+        let sender =
+            TransactionalMtbFileSender::new(producer, 10, Arc::new(AtomicBool::new(false)));
+
+        // Dropping the future while sending, as done if the client disconnects
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), sender.send_batch(batch()))
+                .await
+                .is_err()
+        );
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(operations(&operations_sent), vec!["begin", "commit"]);
+
+        assert!(sender.send_batch(batch()).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn should_abort_transaction_if_begin_fails() {
+        let producer = FakeProducer {
+            fail_begin: AtomicBool::new(true),
+            ..FakeProducer::default()
+        };
+        let operations_sent = Arc::clone(&producer.operations);
+        let failed = Arc::new(AtomicBool::new(false));
+        let sender = TransactionalMtbFileSender::new(producer, 10, Arc::clone(&failed));
+
+        assert!(sender.send_batch(batch()).await.is_err());
+        assert_eq!(operations(&operations_sent), vec!["begin", "abort"]);
+        assert!(!failed.load(Ordering::Relaxed));
+
+        assert!(sender.send_batch(batch()).await.is_ok());
+    }
 }
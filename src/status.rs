@@ -0,0 +1,412 @@
+use rdkafka::Message;
+use rdkafka::consumer::StreamConsumer;
+use rdkafka::message::Headers;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::kafka::KafkaContext;
+
+const MAX_ENTRIES: usize = 100_000;
+/// Number of lines appended to the file before it is rewritten to contain retained requests only
+const COMPACT_AFTER: usize = 2 * MAX_ENTRIES;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RequestStatus {
+    Pending,
+    Success,
+    Warning,
+    Error,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RequestState {
+    pub request_id: String,
+    pub status: RequestStatus,
+    #[serde(default)]
+    pub issues: Vec<Value>,
+}
+
+/// Response record as sent by the ETL processor
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ResponseBody {
+    #[serde(alias = "request_id")]
+    request_id: Option<String>,
+    #[serde(alias = "status_code")]
+    status_code: u16,
+    #[serde(alias = "status_body", default)]
+    status_body: Value,
+}
+
+impl ResponseBody {
+    fn into_request_state(self, request_id: Option<String>) -> Option<RequestState> {
+        let issues = match self.status_body.get("issues") {
+            Some(Value::Array(issues)) => issues.clone(),
+            _ => vec![],
+        };
+
+        let status = match self.status_code {
+            201 => RequestStatus::Warning,
+            200..=299 if issues.is_empty() => RequestStatus::Success,
+            200..=299 => RequestStatus::Warning,
+            _ => RequestStatus::Error,
+        };
+
+        Some(RequestState {
+            request_id: request_id.or(self.request_id)?,
+            status,
+            issues,
+        })
+    }
+}
+
+#[derive(Default)]
+struct Entries {
+    states: HashMap<String, RequestState>,
+    order: VecDeque<String>,
+}
+
+impl Entries {
+    fn insert(&mut self, state: RequestState) {
+        if !self.states.contains_key(&state.request_id) {
+            self.order.push_back(state.request_id.clone());
+            while self.order.len() > MAX_ENTRIES {
+                if let Some(oldest) = self.order.pop_front() {
+                    self.states.remove(&oldest);
+                }
+            }
+        }
+        self.states.insert(state.request_id.clone(), state);
+    }
+
+    /// Returns the latest state of retained requests in insertion order
+    fn latest(&self) -> Vec<RequestState> {
+        self.order
+            .iter()
+            .filter_map(|request_id| self.states.get(request_id).cloned())
+            .collect()
+    }
+}
+
+/// File containing request states as JSON lines
+struct StatusFile {
+    path: PathBuf,
+    file: File,
+    appended: usize,
+    compact_after: usize,
+    /// States appended while the file is compacted, to be appended to the compacted file
+    compacting: Option<Vec<RequestState>>,
+    compaction: Option<JoinHandle<()>>,
+}
+
+impl StatusFile {
+    fn append(&mut self, state: &RequestState) -> io::Result<()> {
+        writeln!(self.file, "{}", serde_json::to_string(state)?)?;
+        self.appended += 1;
+        if let Some(compacting) = &mut self.compacting {
+            compacting.push(state.clone());
+        }
+        Ok(())
+    }
+
+    fn compaction_due(&self) -> bool {
+        self.appended >= self.compact_after && self.compacting.is_none()
+    }
+}
+
+/// Rewrites the file in the background to not block requests. States appended meanwhile are
+/// added to the compacted file before it replaces the file.
+fn compact_in_background(file: &Arc<Mutex<StatusFile>>, entries: &RwLock<Entries>) {
+    let Ok(mut status_file) = file.lock() else {
+        return;
+    };
+    if !status_file.compaction_due() {
+        return;
+    }
+    let Ok(states) = entries.read().map(|entries| entries.latest()) else {
+        return;
+    };
+    status_file.compacting = Some(vec![]);
+    status_file.appended = 0;
+
+    let path = status_file.path.clone();
+    let file = Arc::clone(file);
+    status_file.compaction = Some(std::thread::spawn(move || {
+        let result = write_tmp_file(&path, &states).and_then(|mut tmp_file| {
+            let Ok(mut status_file) = file.lock() else {
+                return Ok(());
+            };
+            let appended = status_file.compacting.take().unwrap_or_default();
+            write_states(&mut tmp_file, &appended)?;
+            fs::rename(tmp_path(&path), &path)?;
+            status_file.file = tmp_file;
+            Ok(())
+        });
+        if let Err(err) = result {
+            log::warn!("Cannot compact request status file: {err}");
+            if let Ok(mut status_file) = file.lock() {
+                status_file.compacting = None;
+            }
+        }
+    }));
+}
+
+fn tmp_path(path: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.tmp", path.display()))
+}
+
+fn write_states(file: &mut File, states: &[RequestState]) -> io::Result<()> {
+    for state in states {
+        writeln!(file, "{}", serde_json::to_string(state)?)?;
+    }
+    Ok(())
+}
+
+/// Writes the states to a temporary file synced to disk and returns it opened for appending
+fn write_tmp_file(path: &Path, states: &[RequestState]) -> io::Result<File> {
+    let mut tmp_file = OpenOptions::new()
+        .create(true)
+        .truncate(true)
+        .write(true)
+        .open(tmp_path(path))?;
+    write_states(&mut tmp_file, states)?;
+    tmp_file.sync_all()?;
+    Ok(tmp_file)
+}
+
+/// Rewrites the file to contain only the latest state of retained requests
+fn compact(path: &Path, entries: &Entries) -> io::Result<()> {
+    write_tmp_file(path, &entries.latest())?;
+    fs::rename(tmp_path(path), path)
+}
+
+/// Index of the processing state of requests, optionally persisted as JSON lines
+pub struct RequestStatusStore {
+    entries: RwLock<Entries>,
+    file: Option<Arc<Mutex<StatusFile>>>,
+}
+
+impl RequestStatusStore {
+    pub fn new() -> Self {
+        Self {
+            entries: RwLock::new(Entries::default()),
+            file: None,
+        }
+    }
+
+    /// Loads known request states from given file and keeps it updated
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::open_with_compaction(path, COMPACT_AFTER)
+    }
+
+    fn open_with_compaction(path: impl AsRef<Path>, compact_after: usize) -> io::Result<Self> {
+        let path = path.as_ref();
+
+        let mut entries = Entries::default();
+        match File::open(path) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    if let Ok(state) = serde_json::from_str::<RequestState>(&line?) {
+                        entries.insert(state);
+                    }
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+
+        compact(path, &entries)?;
+
+        Ok(Self {
+            entries: RwLock::new(entries),
+            file: Some(Arc::new(Mutex::new(StatusFile {
+                path: path.to_path_buf(),
+                file: OpenOptions::new().append(true).open(path)?,
+                appended: 0,
+                compact_after,
+                compacting: None,
+                compaction: None,
+            }))),
+        })
+    }
+
+    /// Marks a request as pending if its state is not known yet
+    pub fn pending(&self, request_id: &str) {
+        if self.get(request_id).is_none() {
+            self.update(RequestState {
+                request_id: request_id.to_string(),
+                status: RequestStatus::Pending,
+                issues: vec![],
+            });
+        }
+    }
+
+    pub fn update(&self, state: RequestState) {
+        // Keeps the file locked to compact it with the latest states only
+        let mut file = self.file.as_ref().and_then(|file| file.lock().ok());
+        if let Some(file) = &mut file
+            && let Err(err) = file.append(&state)
+        {
+            log::warn!(
+                "Cannot persist state of request '{}': {err}",
+                state.request_id
+            );
+        }
+
+        if let Ok(mut entries) = self.entries.write() {
+            entries.insert(state);
+        }
+
+        let compaction_due = file.is_some_and(|file| file.compaction_due());
+        if compaction_due && let Some(file) = &self.file {
+            compact_in_background(file, &self.entries);
+        }
+    }
+
+    pub fn get(&self, request_id: &str) -> Option<RequestState> {
+        self.entries
+            .read()
+            .ok()
+            .and_then(|entries| entries.states.get(request_id).cloned())
+    }
+}
+
+impl Drop for RequestStatusStore {
+    /// Waits for a running compaction to not leave the temporary file behind
+    fn drop(&mut self) {
+        let compaction = self
+            .file
+            .as_ref()
+            .and_then(|file| file.lock().ok())
+            .and_then(|mut file| file.compaction.take());
+        if let Some(compaction) = compaction {
+            let _ = compaction.join();
+        }
+    }
+}
+
+fn request_state(request_id: Option<String>, payload: &[u8]) -> Option<RequestState> {
+    serde_json::from_slice::<ResponseBody>(payload)
+        .ok()?
+        .into_request_state(request_id)
+}
+
+/// Updates request states using response records of the ETL processor
+pub async fn consume(consumer: StreamConsumer<KafkaContext>, store: Arc<RequestStatusStore>) {
+    loop {
+        match consumer.recv().await {
+            Ok(message) => {
+                let request_id = message.headers().and_then(|headers| {
+                    headers
+                        .iter()
+                        .find(|header| header.key == "requestId")
+                        .and_then(|header| header.value)
+                        .and_then(|value| String::from_utf8(value.to_vec()).ok())
+                });
+
+                match message
+                    .payload()
+                    .and_then(|payload| request_state(request_id, payload))
+                {
+                    Some(state) => store.update(state),
+                    None => log::warn!("Cannot read response record '{}'", message.offset()),
+                }
+            }
+            Err(err) => {
+                log::warn!("Cannot receive response record: {err}");
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::status::{RequestStatus, RequestStatusStore, request_state};
+    use rstest::rstest;
+
+    #[rstest]
+    #[case(200, RequestStatus::Success)]
+    #[case(201, RequestStatus::Warning)]
+    #[case(400, RequestStatus::Error)]
+    #[case(422, RequestStatus::Error)]
+    #[case(500, RequestStatus::Error)]
+    fn should_map_status_code(#[case] status_code: u16, #[case] expected: RequestStatus) {
+        let payload =
+            format!(r#"{{"requestId":"abc","statusCode":{status_code},"statusBody":{{}}}}"#);
+        let state = request_state(None, payload.as_bytes());
+        assert_eq!(state.map(|state| state.status), Some(expected));
+    }
+
+    #[test]
+    #[allow(clippy::unwrap_used)]
+    fn should_prefer_request_id_header_and_keep_issues() {
+        let payload = r#"{"request_id":"body","status_code":201,"status_body":{"issues":[{"severity":"warning","message":"Test"}]}}"#;
+        let state = request_state(Some("header".into()), payload.as_bytes()).unwrap();
+        assert_eq!(state.request_id, "header");
+        assert_eq!(state.status, RequestStatus::Warning);
+        assert_eq!(state.issues.len(), 1);
+    }
+
+    #[test]
+    fn should_not_read_response_without_request_id() {
+        assert!(request_state(None, br#"{"statusCode":200}"#).is_none());
+    }
+
+    #[test]
+    #[allow(clippy::unwrap_used)]
+    fn should_keep_pending_state_until_updated() {
+        let store = RequestStatusStore::new();
+        store.pending("abc");
+        assert_eq!(store.get("abc").unwrap().status, RequestStatus::Pending);
+
+        store.update(request_state(None, br#"{"requestId":"abc","statusCode":200}"#).unwrap());
+        store.pending("abc");
+        assert_eq!(store.get("abc").unwrap().status, RequestStatus::Success);
+    }
+
+    #[test]
+    #[allow(clippy::unwrap_used)]
+    fn should_load_persisted_states() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("request-status.jsonl");
+        {
+            let store = RequestStatusStore::open(&path).unwrap();
+            store.pending("abc");
+            store.update(request_state(None, br#"{"requestId":"abc","statusCode":400}"#).unwrap());
+        }
+
+        let store = RequestStatusStore::open(&path).unwrap();
+        assert_eq!(store.get("abc").unwrap().status, RequestStatus::Error);
+    }
+
+    #[test]
+    #[allow(clippy::unwrap_used)]
+    fn should_compact_file_while_running() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("request-status.jsonl");
+        let store = RequestStatusStore::open_with_compaction(&path, 3).unwrap();
+
+        store.pending("abc");
+        store.update(request_state(None, br#"{"requestId":"abc","statusCode":200}"#).unwrap());
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 2);
+        store.pending("def");
+        store.update(request_state(None, br#"{"requestId":"def","statusCode":400}"#).unwrap());
+        // Waits for the compaction
+        drop(store);
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 3);
+
+        let store = RequestStatusStore::open(&path).unwrap();
+        assert_eq!(store.get("abc").unwrap().status, RequestStatus::Success);
+        assert_eq!(store.get("def").unwrap().status, RequestStatus::Error);
+    }
+}
@@ -0,0 +1,195 @@
+use axum::http::{HeaderMap, HeaderName};
+use opentelemetry::Context;
+use opentelemetry::propagation::TextMapPropagator;
+use opentelemetry::trace::{TraceContextExt, TracerProvider};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::Resource;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::Layer;
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::CONFIG;
+
+pub const TRACEPARENT: HeaderName = HeaderName::from_static("traceparent");
+pub const TRACESTATE: HeaderName = HeaderName::from_static("tracestate");
+pub const CORRELATION_ID: HeaderName = HeaderName::from_static("x-correlation-id");
+
+const MAX_CORRELATION_ID_LENGTH: usize = 255;
+
+/// W3C trace context and correlation ID of a request, passed on in Kafka headers
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TraceContext {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub traceparent: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tracestate: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+}
+
+impl TraceContext {
+    /// Takes a valid `traceparent` with its `tracestate` and the `X-Correlation-Id` of the request.
+    /// Invalid values are ignored as required by W3C trace context.
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let header = |name: &HeaderName| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+
+        let mut trace_context = Self {
+            traceparent: header(&TRACEPARENT),
+            tracestate: header(&TRACESTATE),
+            correlation_id: header(&CORRELATION_ID).filter(|correlation_id| {
+                !correlation_id.is_empty() && correlation_id.len() <= MAX_CORRELATION_ID_LENGTH
+            }),
+        };
+        if !trace_context.parent().span().span_context().is_valid() {
+            trace_context.traceparent = None;
+            trace_context.tracestate = None;
+        }
+        trace_context
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+
+    /// Remote context of the request, the parent of spans sending its MTB file
+    pub fn parent(&self) -> Context {
+        let carrier = [
+            ("traceparent", &self.traceparent),
+            ("tracestate", &self.tracestate),
+        ]
+        .into_iter()
+        .filter_map(|(key, value)| Some((key.to_string(), value.clone()?)))
+        .collect::<HashMap<_, _>>();
+        TraceContextPropagator::new().extract(&carrier)
+    }
+
+    /// Kafka headers containing the trace context of the span and the correlation ID. Without
+    /// a recorded span, the trace context of the request is passed on unchanged.
+    pub fn kafka_headers(&self, span: &Span) -> Vec<(&'static str, String)> {
+        let mut carrier = HashMap::new();
+        let context = span.context();
+        if context.span().span_context().is_valid() {
+            TraceContextPropagator::new().inject_context(&context, &mut carrier);
+        }
+
+        let mut headers = vec![];
+        for (key, value) in [
+            ("traceparent", &self.traceparent),
+            ("tracestate", &self.tracestate),
+        ] {
+            if let Some(value) = carrier.remove(key).or_else(|| value.clone()) {
+                headers.push((key, value));
+            }
+        }
+        if let Some(correlation_id) = &self.correlation_id {
+            headers.push(("correlationId", correlation_id.clone()));
+        }
+        headers
+    }
+}
+
+/// Creates a tracer provider exporting spans to the configured OTLP endpoint. Without endpoint,
+/// spans are still created to pass on trace contexts to Kafka.
+pub fn tracer_provider() -> Result<SdkTracerProvider, String> {
+    let resource = Resource::builder()
+        .with_service_name(CONFIG.otlp_service_name.clone())
+        .build();
+    let mut provider = SdkTracerProvider::builder().with_resource(resource);
+
+    if let Some(endpoint) = &CONFIG.otlp_endpoint {
+        let exporter = SpanExporter::builder()
+            .with_http()
+            .with_endpoint(format!("{}/v1/traces", endpoint.trim_end_matches('/')))
+            .build()
+            .map_err(|err| format!("Cannot create OTLP exporter for '{endpoint}': {err}"))?;
+        provider = provider.with_batch_exporter(exporter);
+    }
+    Ok(provider.build())
+}
+
+/// Layer recording spans using the tracer provider
+pub fn layer<S>(provider: &SdkTracerProvider) -> impl Layer<S>
+where
+    S: tracing::Subscriber + for<'span> LookupSpan<'span>,
+{
+    tracing_opentelemetry::layer().with_tracer(provider.tracer(env!("CARGO_PKG_NAME")))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::telemetry::{CORRELATION_ID, TRACEPARENT, TRACESTATE, TraceContext, layer};
+    use axum::http::{HeaderMap, HeaderValue};
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    const TRACEPARENT_VALUE: &str = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+
+    fn headers(traceparent: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(TRACEPARENT, HeaderValue::from_static(traceparent));
+        headers.insert(TRACESTATE, HeaderValue::from_static("vendor=value"));
+        headers.insert(CORRELATION_ID, HeaderValue::from_static("order-42"));
+        headers
+    }
+
+    #[test]
+    fn should_ignore_invalid_traceparent() {
+        let trace_context = TraceContext::from_headers(&headers(TRACEPARENT_VALUE));
+        assert_eq!(
+            trace_context.traceparent.as_deref(),
+            Some(TRACEPARENT_VALUE)
+        );
+        assert_eq!(trace_context.tracestate.as_deref(), Some("vendor=value"));
+
+        let trace_context = TraceContext::from_headers(&headers(
+            "00-00000000000000000000000000000000-b7ad6b7169203331-01",
+        ));
+        assert_eq!(trace_context.traceparent, None);
+        assert_eq!(trace_context.tracestate, None);
+        assert_eq!(trace_context.correlation_id.as_deref(), Some("order-42"));
+    }
+
+    #[test]
+    fn should_pass_on_trace_context_without_recorded_span() {
+        let trace_context = TraceContext::from_headers(&headers(TRACEPARENT_VALUE));
+
+        assert_eq!(
+            trace_context.kafka_headers(&tracing::Span::none()),
+            vec![
+                ("traceparent", TRACEPARENT_VALUE.to_string()),
+                ("tracestate", "vendor=value".to_string()),
+                ("correlationId", "order-42".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn should_write_trace_context_of_produce_span() {
+        let provider = SdkTracerProvider::builder().build();
+        let subscriber = tracing_subscriber::registry().with(layer(&provider));
+        let trace_context = TraceContext::from_headers(&headers(TRACEPARENT_VALUE));
+
+        let headers = tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("produce");
+            let _ = span.set_parent(trace_context.parent());
+            trace_context.kafka_headers(&span)
+        });
+
+        let traceparent = &headers[0].1;
+        assert!(traceparent.starts_with("00-0af7651916cd43dd8448eb211c80319c-"));
+        assert_ne!(traceparent, TRACEPARENT_VALUE);
+        assert_eq!(headers[1], ("tracestate", "vendor=value".to_string()));
+    }
+}
@@ -0,0 +1,534 @@
+use axum::Router;
+use axum::http::Request;
+use clap::ValueEnum;
+use hyper::body::Incoming;
+use hyper::service::service_fn;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use rustls::RootCertStore;
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+use std::fmt::{Debug, Formatter};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+use tower::ServiceExt;
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+use crate::auth::AuthError;
+
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Delay before accepting connections again, e.g. if no file descriptors are available
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// How a client certificate is used to authenticate requests
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ClientCertAuth {
+    /// HTTP authentication is required in addition to the client certificate
+    Complement,
+    /// An allowed client certificate replaces HTTP authentication
+    Replace,
+}
+
+/// Verified client certificate of the connection a request was received on
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClientCertificate {
+    /// Subject DN, e.g. `CN=localhost`
+    pub subject: String,
+    /// DNS names, e-mail addresses and URIs of the subject alternative name extension
+    pub alt_names: Vec<String>,
+}
+
+impl ClientCertificate {
+    pub fn parse(cert: &CertificateDer) -> Option<Self> {
+        let (_, cert) = X509Certificate::from_der(cert).ok()?;
+        let alt_names = match cert.subject_alternative_name() {
+            Ok(Some(extension)) => extension
+                .value
+                .general_names
+                .iter()
+                .filter_map(|name| match name {
+                    GeneralName::DNSName(name)
+                    | GeneralName::RFC822Name(name)
+                    | GeneralName::URI(name) => Some((*name).to_string()),
+                    _ => None,
+                })
+                .collect(),
+            _ => vec![],
+        };
+        Some(Self {
+            subject: cert.subject().to_string(),
+            alt_names,
+        })
+    }
+
+    /// Checks if subject DN or one of the alternative names is allowed.
+    /// Any certificate is allowed if the allow list is empty.
+    pub fn is_allowed(&self, allow_list: &[String]) -> bool {
+        allow_list.is_empty()
+            || allow_list.iter().any(|allowed| {
+                allowed.eq_ignore_ascii_case(&self.subject)
+                    || self.alt_names.iter().any(|name| name == allowed)
+            })
+    }
+
+    /// Returns the subject DN if the client certificate replaces HTTP authentication
+    pub fn authenticate(
+        &self,
+        allow_list: &[String],
+        mode: ClientCertAuth,
+    ) -> Result<Option<String>, AuthError> {
+        if !self.is_allowed(allow_list) {
+            return Err(AuthError::Forbidden(self.subject.clone()));
+        }
+        match mode {
+            ClientCertAuth::Complement => Ok(None),
+            ClientCertAuth::Replace => Ok(Some(self.subject.clone())),
+        }
+    }
+}
+
+/// Authenticates a request by the client certificate of the connection, if any. If client
+/// certificates are required, requests without one are rejected.
+pub fn authenticate_client(
+    client_certificate: Option<&ClientCertificate>,
+    required: bool,
+    allow_list: &[String],
+    mode: ClientCertAuth,
+) -> Result<Option<String>, AuthError> {
+    match client_certificate {
+        Some(client_certificate) => client_certificate.authenticate(allow_list, mode),
+        None if required => Err(AuthError::Unauthorized),
+        None => Ok(None),
+    }
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+fn load_certified_key(cert_file: &str, key_file: &str) -> Result<CertifiedKey, String> {
+    let certs = CertificateDer::pem_file_iter(cert_file)
+        .and_then(Iterator::collect::<Result<Vec<_>, _>>)
+        .map_err(|err| format!("Cannot read certificate file '{cert_file}': {err}"))?;
+    if certs.is_empty() {
+        return Err(format!("No certificate in file '{cert_file}'"));
+    }
+    let key = PrivateKeyDer::from_pem_file(key_file)
+        .map_err(|err| format!("Cannot read key file '{key_file}': {err}"))?;
+    CertifiedKey::from_der(certs, key, &provider())
+        .map_err(|err| format!("Invalid certificate or key: {err}"))
+}
+
+fn modified(cert_file: &str, key_file: &str) -> Option<(SystemTime, SystemTime)> {
+    let cert_modified = std::fs::metadata(cert_file).and_then(|m| m.modified());
+    let key_modified = std::fs::metadata(key_file).and_then(|m| m.modified());
+    cert_modified.ok().zip(key_modified.ok())
+}
+
+/// Provides the server certificate and reloads it if certificate or key file changed
+pub struct ReloadingCertResolver {
+    cert_file: String,
+    key_file: String,
+    certified_key: RwLock<Arc<CertifiedKey>>,
+    modified: Mutex<Option<(SystemTime, SystemTime)>>,
+}
+
+impl ReloadingCertResolver {
+    pub fn new(cert_file: &str, key_file: &str) -> Result<Self, String> {
+        let modified = modified(cert_file, key_file);
+        let certified_key = load_certified_key(cert_file, key_file)?;
+        Ok(Self {
+            cert_file: cert_file.to_string(),
+            key_file: key_file.to_string(),
+            certified_key: RwLock::new(Arc::new(certified_key)),
+            modified: Mutex::new(modified),
+        })
+    }
+
+    /// Reloads certificate and key if one of the files was modified.
+    /// Returns `true` if reloaded.
+    pub fn reload_if_modified(&self) -> Result<bool, String> {
+        let modified = modified(&self.cert_file, &self.key_file);
+        let Ok(mut last_modified) = self.modified.lock() else {
+            return Ok(false);
+        };
+        if modified == *last_modified {
+            return Ok(false);
+        }
+
+        // Remember modification time to not log the same error again
+        *last_modified = modified;
+        let certified_key = load_certified_key(&self.cert_file, &self.key_file)?;
+        if let Ok(mut current) = self.certified_key.write() {
+            *current = Arc::new(certified_key);
+        }
+        Ok(true)
+    }
+}
+
+impl Debug for ReloadingCertResolver {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ReloadingCertResolver")
+            .field("cert_file", &self.cert_file)
+            .field("key_file", &self.key_file)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ResolvesServerCert for ReloadingCertResolver {
+    fn resolve(&self, _: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.certified_key.read().ok().map(|key| Arc::clone(&key))
+    }
+}
+
+/// Checks periodically for modified certificate or key files
+pub async fn reload_periodically(resolver: Arc<ReloadingCertResolver>) {
+    let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+    loop {
+        interval.tick().await;
+        match resolver.reload_if_modified() {
+            Ok(true) => log::info!("Reloaded TLS certificate '{}'", resolver.cert_file),
+            Ok(false) => {}
+            Err(err) => log::error!("Cannot reload TLS certificate, keeping previous one: {err}"),
+        }
+    }
+}
+
+/// Creates the TLS server config. Client certificates are verified if a CA file is given.
+/// Connections without client certificate are accepted to serve health checks and metrics,
+/// requests to send MTB files are rejected by `authenticate_client()`.
+pub fn server_config(
+    resolver: Arc<ReloadingCertResolver>,
+    client_ca_file: Option<&str>,
+) -> Result<rustls::ServerConfig, String> {
+    let builder = rustls::ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(|err| err.to_string())?;
+
+    let builder = match client_ca_file {
+        Some(client_ca_file) => {
+            let mut roots = RootCertStore::empty();
+            for cert in CertificateDer::pem_file_iter(client_ca_file)
+                .map_err(|err| format!("Cannot read CA file '{client_ca_file}': {err}"))?
+            {
+                let cert =
+                    cert.map_err(|err| format!("Invalid CA file '{client_ca_file}': {err}"))?;
+                roots
+                    .add(cert)
+                    .map_err(|err| format!("Invalid CA file '{client_ca_file}': {err}"))?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider())
+                .allow_unauthenticated()
+                .build()
+                .map_err(|err| err.to_string())?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let mut config = builder.with_cert_resolver(resolver);
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+/// Serves HTTPS requests. The verified client certificate, if any, is added to each request.
+pub async fn serve(listener: TcpListener, app: Router, config: rustls::ServerConfig) {
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+    loop {
+        let (stream, remote_addr) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(err) => {
+                log::error!("Cannot accept connection: {err}");
+                tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                continue;
+            }
+        };
+
+        let acceptor = acceptor.clone();
+        let app = app.clone();
+        tokio::spawn(async move {
+            let stream =
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => stream,
+                    Ok(Err(err)) => {
+                        log::warn!("TLS handshake with '{remote_addr}' failed: {err}");
+                        return;
+                    }
+                    Err(_) => {
+                        log::warn!("TLS handshake with '{remote_addr}' timed out");
+                        return;
+                    }
+                };
+            let client_certificate = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+                .and_then(ClientCertificate::parse);
+
+            let service = service_fn(move |mut request: Request<Incoming>| {
+                if let Some(client_certificate) = &client_certificate {
+                    request.extensions_mut().insert(client_certificate.clone());
+                }
+                app.clone().oneshot(request)
+            });
+            if let Err(err) = auto::Builder::new(TokioExecutor::new())
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                log::debug!("Connection with '{remote_addr}' failed: {err}");
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::auth::AuthError;
+    use crate::tls::{
+        ClientCertAuth, ClientCertificate, ReloadingCertResolver, authenticate_client, serve,
+        server_config,
+    };
+    use axum::routing::get;
+    use axum::{Extension, Router};
+    use rustls::DigitallySignedStruct;
+    use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+    use rustls::pki_types::pem::PemObject;
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::TlsConnector;
+
+    const CA_FILE: &str = "ssl-dev-fixture/ca.pem";
+    const CERT_FILE: &str = "ssl-dev-fixture/cert.pem";
+    const KEY_FILE: &str = "ssl-dev-fixture/key.pem";
+
+    /// Accepts any server certificate, since the fixture has no subject alternative name
+    #[derive(Debug)]
+    struct AnyServerCertificate;
+
+    impl ServerCertVerifier for AnyServerCertificate {
+        fn verify_server_cert(
+            &self,
+            _: &CertificateDer<'_>,
+            _: &[CertificateDer<'_>],
+            _: &ServerName<'_>,
+            _: &[u8],
+            _: UnixTime,
+        ) -> Result<ServerCertVerified, rustls::Error> {
+            Ok(ServerCertVerified::assertion())
+        }
+
+        fn verify_tls12_signature(
+            &self,
+            _: &[u8],
+            _: &CertificateDer<'_>,
+            _: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            Ok(HandshakeSignatureValid::assertion())
+        }
+
+        fn verify_tls13_signature(
+            &self,
+            _: &[u8],
+            _: &CertificateDer<'_>,
+            _: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            Ok(HandshakeSignatureValid::assertion())
+        }
+
+        fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+            rustls::crypto::ring::default_provider()
+                .signature_verification_algorithms
+                .supported_schemes()
+        }
+    }
+
+    #[allow(clippy::expect_used)]
+    fn fixture_certificate() -> ClientCertificate {
+        let cert = CertificateDer::from_pem_file(CERT_FILE).expect("certificate read");
+        ClientCertificate::parse(&cert).expect("certificate parsed")
+    }
+
+    #[test]
+    fn should_parse_client_certificate() {
+        assert_eq!(
+            fixture_certificate(),
+            ClientCertificate {
+                subject: "CN=localhost".to_string(),
+                alt_names: vec![],
+            }
+        );
+    }
+
+    #[test]
+    fn should_check_allow_list() {
+        let cert = ClientCertificate {
+            subject: "CN=lab-import, O=Example".to_string(),
+            alt_names: vec!["lab-import.example.com".to_string()],
+        };
+
+        assert!(cert.is_allowed(&[]));
+        assert!(cert.is_allowed(&["CN=lab-import, O=Example".to_string()]));
+        assert!(cert.is_allowed(&["CN=other".to_string(), "lab-import.example.com".to_string()]));
+        assert!(!cert.is_allowed(&["CN=other".to_string()]));
+    }
+
+    #[test]
+    fn should_authenticate_by_client_certificate() {
+        let cert = fixture_certificate();
+
+        assert_eq!(cert.authenticate(&[], ClientCertAuth::Complement), Ok(None));
+        assert_eq!(
+            cert.authenticate(&["CN=localhost".to_string()], ClientCertAuth::Replace),
+            Ok(Some("CN=localhost".to_string()))
+        );
+        assert_eq!(
+            cert.authenticate(&["CN=other".to_string()], ClientCertAuth::Replace),
+            Err(AuthError::Forbidden("CN=localhost".to_string()))
+        );
+    }
+
+    #[test]
+    fn should_require_client_certificate() {
+        let cert = fixture_certificate();
+
+        assert_eq!(
+            authenticate_client(Some(&cert), true, &[], ClientCertAuth::Replace),
+            Ok(Some("CN=localhost".to_string()))
+        );
+        assert_eq!(
+            authenticate_client(None, true, &[], ClientCertAuth::Replace),
+            Err(AuthError::Unauthorized)
+        );
+        assert_eq!(
+            authenticate_client(None, false, &[], ClientCertAuth::Replace),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    #[allow(clippy::expect_used)]
+    fn should_reload_modified_certificate() {
+        let dir = tempfile::tempdir().expect("temp dir");
+        let cert_file = dir.path().join("cert.pem");
+        let key_file = dir.path().join("key.pem");
+        std::fs::copy(CERT_FILE, &cert_file).expect("certificate copied");
+        std::fs::copy(KEY_FILE, &key_file).expect("key copied");
+        let (cert_file, key_file) = (
+            cert_file.to_str().expect("path").to_string(),
+            key_file.to_str().expect("path").to_string(),
+        );
+
+        let resolver = ReloadingCertResolver::new(&cert_file, &key_file).expect("resolver");
+        assert_eq!(resolver.reload_if_modified(), Ok(false));
+
+        let key_pair = rcgen::KeyPair::generate().expect("key pair");
+        let cert = rcgen::CertificateParams::new(vec!["localhost".to_string()])
+            .expect("params")
+            .self_signed(&key_pair)
+            .expect("certificate");
+        std::fs::write(&cert_file, cert.pem()).expect("certificate written");
+        std::fs::write(&key_file, key_pair.serialize_pem()).expect("key written");
+        // Make sure modification time differs on file systems with low resolution
+        let modified = std::time::SystemTime::now() + std::time::Duration::from_secs(1);
+        std::fs::File::options()
+            .write(true)
+            .open(&cert_file)
+            .and_then(|file| file.set_modified(modified))
+            .expect("modification time set");
+
+        assert_eq!(resolver.reload_if_modified(), Ok(true));
+        let current = resolver.certified_key.read().expect("certified key");
+        assert_eq!(current.cert.first(), Some(cert.der()));
+    }
+
+    #[allow(clippy::expect_used)]
+    async fn get_using(
+        connector: &TlsConnector,
+        addr: std::net::SocketAddr,
+    ) -> std::io::Result<String> {
+        let stream = tokio::net::TcpStream::connect(addr)
+            .await
+            .expect("connected");
+        let mut stream = connector
+            .connect(ServerName::try_from("localhost").expect("name"), stream)
+            .await?;
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        Ok(response)
+    }
+
+    #[tokio::test]
+    #[allow(clippy::expect_used)]
+    async fn should_verify_client_certificate() {
+        let resolver = ReloadingCertResolver::new(CERT_FILE, KEY_FILE).expect("resolver");
+        let config = server_config(Arc::new(resolver), Some(CA_FILE)).expect("server config");
+
+        let app = Router::new().route(
+            "/",
+            get(|cert: Option<Extension<ClientCertificate>>| async move {
+                cert.map_or_else(|| "none".to_string(), |Extension(cert)| cert.subject)
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("listener bound");
+        let addr = listener.local_addr().expect("local address");
+        tokio::spawn(serve(listener, app, config));
+
+        let builder = rustls::ClientConfig::builder_with_provider(Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()
+        .expect("protocol versions")
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(AnyServerCertificate));
+
+        // Using client certificate
+        let connector = TlsConnector::from(Arc::new(
+            builder
+                .clone()
+                .with_client_auth_cert(
+                    vec![CertificateDer::from_pem_file(CERT_FILE).expect("certificate")],
+                    PrivateKeyDer::from_pem_file(KEY_FILE).expect("key"),
+                )
+                .expect("client config"),
+        ));
+        let response = get_using(&connector, addr).await.expect("response");
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("CN=localhost"));
+
+        // Without client certificate, e.g. for health checks
+        let connector = TlsConnector::from(Arc::new(builder.clone().with_no_client_auth()));
+        let response = get_using(&connector, addr).await.expect("response");
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("none"));
+
+        // Using client certificate not issued by the CA
+        let key_pair = rcgen::KeyPair::generate().expect("key pair");
+        let cert = rcgen::CertificateParams::new(vec!["localhost".to_string()])
+            .expect("params")
+            .self_signed(&key_pair)
+            .expect("certificate");
+        let connector = TlsConnector::from(Arc::new(
+            builder
+                .with_client_auth_cert(
+                    vec![cert.der().clone()],
+                    PrivateKeyDer::from_pem_slice(key_pair.serialize_pem().as_bytes())
+                        .expect("key"),
+                )
+                .expect("client config"),
+        ));
+        assert!(get_using(&connector, addr).await.is_err());
+    }
+}
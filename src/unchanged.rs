@@ -0,0 +1,421 @@
+use async_trait::async_trait;
+use mv64e_mtb_dto::Mtb;
+use redb::{Database, ReadableDatabase, TableDefinition};
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::idempotency::{ContentHash, content_hash};
+use crate::sender::{DynMtbFileSender, MtbFileSender, SendOptions, SendReport};
+
+/// Content hash and request ID of the MTB file last sent by hashed topic and patient ID
+const SENT_MTB_FILES: TableDefinition<&[u8; 32], (ContentHash, &str)> =
+    TableDefinition::new("sent_mtb_files_by_key");
+
+/// Previously used table containing plain patient IDs, removed on open
+const PLAIN_SENT_MTB_FILES: TableDefinition<&str, (ContentHash, &str)> =
+    TableDefinition::new("sent_mtb_files");
+
+/// Key of the MTB file last sent for the patient to the topic. Patient IDs are hashed to not
+/// store them in plain text, as they might be the original ones if pseudonymization is used.
+pub type PatientKey = [u8; 32];
+
+pub fn patient_key(topic: &str, patient_id: &str) -> PatientKey {
+    let mut hasher = Sha256::new();
+    hasher.update(topic.as_bytes());
+    hasher.update([0]);
+    hasher.update(patient_id.as_bytes());
+    hasher.finalize().into()
+}
+
+/// Content hashes of sent MTB files, persisted in an embedded key-value store
+pub struct ContentHashStore {
+    db: Database,
+}
+
+impl ContentHashStore {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, redb::Error> {
+        let db = Database::create(path)?;
+        // Create the table to allow reading from it
+        let transaction = db.begin_write()?;
+        transaction.open_table(SENT_MTB_FILES)?;
+        transaction.delete_table(PLAIN_SENT_MTB_FILES)?;
+        transaction.commit()?;
+        Ok(Self { db })
+    }
+
+    /// Returns the request ID of the last MTB file of the patient if it has the same content hash
+    pub fn previous(
+        &self,
+        key: &PatientKey,
+        hash: &ContentHash,
+    ) -> Result<Option<String>, redb::Error> {
+        let transaction = self.db.begin_read()?;
+        let table = transaction.open_table(SENT_MTB_FILES)?;
+        Ok(table.get(key)?.and_then(|entry| {
+            let (previous_hash, request_id) = entry.value();
+            (previous_hash == *hash).then(|| request_id.to_string())
+        }))
+    }
+
+    /// Remembers the content hash of the MTB file last sent for the patient
+    pub fn store(
+        &self,
+        key: &PatientKey,
+        hash: &ContentHash,
+        request_id: &str,
+    ) -> Result<(), redb::Error> {
+        let transaction = self.db.begin_write()?;
+        transaction
+            .open_table(SENT_MTB_FILES)?
+            .insert(key, (*hash, request_id))?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// Removes content hashes stored for the request, e.g. if its MTB file could not be sent
+    /// after being accepted, to send the MTB file again on the next request
+    pub fn remove(&self, request_id: &str) -> Result<(), redb::Error> {
+        let transaction = self.db.begin_write()?;
+        transaction
+            .open_table(SENT_MTB_FILES)?
+            .retain(|_, (_, stored_request_id)| stored_request_id != request_id)?;
+        transaction.commit()?;
+        Ok(())
+    }
+}
+
+/// Sends MTB files using the wrapped sender unless unchanged since the last MTB file of the patient
+/// sent to the same topic
+#[allow(clippy::module_name_repetitions)]
+pub struct UnchangedSkippingMtbFileSender {
+    store: Arc<ContentHashStore>,
+    sender: DynMtbFileSender,
+    default_topic: String,
+}
+
+impl UnchangedSkippingMtbFileSender {
+    pub fn new(
+        store: Arc<ContentHashStore>,
+        sender: DynMtbFileSender,
+        default_topic: &str,
+    ) -> Self {
+        Self {
+            store,
+            sender,
+            default_topic: default_topic.to_string(),
+        }
+    }
+
+    fn key(&self, mtb: &Mtb, options: &SendOptions) -> PatientKey {
+        let topic = options.topic.as_deref().unwrap_or(&self.default_topic);
+        patient_key(topic, &mtb.patient.id)
+    }
+
+    /// Returns the request ID of the unchanged last MTB file of the patient
+    async fn previous(&self, key: PatientKey, hash: ContentHash) -> Option<String> {
+        let store = Arc::clone(&self.store);
+        match tokio::task::spawn_blocking(move || store.previous(&key, &hash)).await {
+            Ok(Ok(request_id)) => request_id,
+            // Sending an unchanged MTB file again is preferred to rejecting it
+            Ok(Err(err)) => {
+                log::warn!("Cannot read content hash of last MTB file: {err}");
+                None
+            }
+            Err(_) => {
+                log::warn!("Cannot read content hash of last MTB file");
+                None
+            }
+        }
+    }
+
+    async fn store(&self, key: PatientKey, hash: ContentHash, request_id: &str) {
+        let store = Arc::clone(&self.store);
+        let request_id = request_id.to_string();
+        match tokio::task::spawn_blocking(move || store.store(&key, &hash, &request_id)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => log::warn!("Cannot store content hash of MTB file: {err}"),
+            Err(_) => log::warn!("Cannot store content hash of MTB file"),
+        }
+    }
+
+    async fn remove(&self, request_id: &str) {
+        let store = Arc::clone(&self.store);
+        let request_id = request_id.to_string();
+        match tokio::task::spawn_blocking(move || store.remove(&request_id)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => log::warn!("Cannot remove content hash of MTB file: {err}"),
+            Err(_) => log::warn!("Cannot remove content hash of MTB file"),
+        }
+    }
+}
+
+/// Returns the request ID, assigned before sending to store the content hash beforehand. Using an
+/// outbox, the content hash is removed if the MTB file is moved to the dead letter file, which
+/// might happen before the sender returns.
+fn assign_request_id(options: &mut SendOptions) -> String {
+    options
+        .request_id
+        .get_or_insert_with(|| Uuid::new_v4().to_string())
+        .clone()
+}
+
+#[async_trait]
+impl MtbFileSender for UnchangedSkippingMtbFileSender {
+    async fn send(&self, mtb: Mtb, mut options: SendOptions) -> Result<SendReport, ()> {
+        let key = self.key(&mtb, &options);
+        let hash = content_hash(&mtb);
+        if let Some(request_id) = self.previous(key, hash).await {
+            log::info!("MTB file unchanged since request '{request_id}'");
+            return Ok(SendReport::unchanged(&request_id));
+        }
+
+        let request_id = assign_request_id(&mut options);
+        self.store(key, hash, &request_id).await;
+        let report = self.sender.send(mtb, options).await;
+        if report.is_err() {
+            self.remove(&request_id).await;
+        }
+        report
+    }
+
+    async fn send_batch(&self, batch: Vec<(Mtb, SendOptions)>) -> Result<Vec<SendReport>, ()> {
+        let mut unchanged = vec![];
+        let mut changed = vec![];
+        let mut request_ids = vec![];
+        for (mtb, mut options) in batch {
+            let key = self.key(&mtb, &options);
+            let hash = content_hash(&mtb);
+            if let Some(request_id) = self.previous(key, hash).await {
+                log::info!("MTB file unchanged since request '{request_id}'");
+                unchanged.push(Some(SendReport::unchanged(&request_id)));
+            } else {
+                let request_id = assign_request_id(&mut options);
+                self.store(key, hash, &request_id).await;
+                unchanged.push(None);
+                changed.push((mtb, options));
+                request_ids.push(request_id);
+            }
+        }
+
+        let Ok(reports) = self.sender.send_batch(changed).await else {
+            for request_id in &request_ids {
+                self.remove(request_id).await;
+            }
+            return Err(());
+        };
+        let mut reports = reports.into_iter();
+        // Keep the order of the batch
+        Ok(unchanged
+            .into_iter()
+            .filter_map(|report| report.or_else(|| reports.next()))
+            .collect())
+    }
+
+    fn transactional(&self) -> bool {
+        self.sender.transactional()
+    }
+
+    fn max_batch_size(&self) -> Option<usize> {
+        self.sender.max_batch_size()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::idempotency::content_hash;
+    use crate::sender::{
+        DynMtbFileSender, MockMtbFileSender, MtbFileSender, SendOptions, SendReport,
+    };
+    use crate::unchanged::{ContentHashStore, UnchangedSkippingMtbFileSender, patient_key};
+    use mv64e_mtb_dto::Mtb;
+    use std::sync::Arc;
+
+    #[allow(clippy::expect_used)]
+    fn fake_mtb_file(diagnosis_code: &str) -> Mtb {
+        serde_json::from_str(
+            &include_str!("../test-files/mv64e-mtb-fake-patient.json")
+                .replace("\"C72.5\"", &format!("\"{diagnosis_code}\"")),
+        )
+        .expect("valid MTB file")
+    }
+
+    fn options(request_id: &str) -> SendOptions {
+        SendOptions {
+            request_id: Some(request_id.to_string()),
+            ..SendOptions::default()
+        }
+    }
+
+    #[test]
+    #[allow(clippy::unwrap_used)]
+    fn should_return_previous_request_id_of_same_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("unchanged.redb");
+        let p1 = patient_key("topic", "P1");
+        let p2 = patient_key("topic", "P2");
+
+        let store = ContentHashStore::open(&path).unwrap();
+        assert_eq!(store.previous(&p1, &[1; 32]).unwrap(), None);
+        store.store(&p1, &[1; 32], "request-1").unwrap();
+        assert_eq!(
+            store.previous(&p1, &[1; 32]).unwrap(),
+            Some("request-1".to_string())
+        );
+        assert_eq!(store.previous(&p1, &[2; 32]).unwrap(), None);
+        assert_eq!(store.previous(&p2, &[1; 32]).unwrap(), None);
+        assert_eq!(
+            store
+                .previous(&patient_key("other-topic", "P1"), &[1; 32])
+                .unwrap(),
+            None
+        );
+        drop(store);
+
+        let store = ContentHashStore::open(&path).unwrap();
+        assert_eq!(
+            store.previous(&p1, &[1; 32]).unwrap(),
+            Some("request-1".to_string())
+        );
+        store.store(&p2, &[1; 32], "request-2").unwrap();
+        store.remove("request-1").unwrap();
+        assert_eq!(store.previous(&p1, &[1; 32]).unwrap(), None);
+        assert_eq!(
+            store.previous(&p2, &[1; 32]).unwrap(),
+            Some("request-2".to_string())
+        );
+        drop(store);
+
+        // Patient IDs are not stored in plain text
+        assert!(
+            !std::fs::read(&path)
+                .unwrap()
+                .windows(2)
+                .any(|window| window == b"P1")
+        );
+    }
+
+    #[tokio::test]
+    #[allow(clippy::unwrap_used)]
+    async fn should_skip_unchanged_mtb_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = ContentHashStore::open(dir.path().join("unchanged.redb")).unwrap();
+
+        let mut sender_mock = MockMtbFileSender::new();
+        sender_mock
+            .expect_send()
+            .times(3)
+            .returning(|_, options| Ok(SendReport::accepted(&options.request_id.unwrap())));
+
+        let sender = UnchangedSkippingMtbFileSender::new(
+            Arc::new(store),
+            Arc::new(sender_mock) as DynMtbFileSender,
+            "topic",
+        );
+
+        let report = sender
+            .send(fake_mtb_file("C72.5"), options("request-1"))
+            .await
+            .unwrap();
+        assert_eq!(report.request_id, "request-1");
+        assert!(!report.unchanged);
+
+        let report = sender
+            .send(fake_mtb_file("C72.5"), options("request-2"))
+            .await
+            .unwrap();
+        assert_eq!(report.request_id, "request-1");
+        assert!(report.unchanged);
+
+        let report = sender
+            .send(fake_mtb_file("C71.0"), options("request-2"))
+            .await
+            .unwrap();
+        assert_eq!(report.request_id, "request-2");
+        assert!(!report.unchanged);
+
+        // Sent again if routed to another topic
+        let options = SendOptions {
+            topic: Some("other-topic".to_string()),
+            ..options("request-3")
+        };
+        let report = sender.send(fake_mtb_file("C71.0"), options).await.unwrap();
+        assert_eq!(report.request_id, "request-3");
+        assert!(!report.unchanged);
+    }
+
+    #[tokio::test]
+    #[allow(clippy::unwrap_used)]
+    async fn should_send_mtb_file_again_if_not_sent() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = ContentHashStore::open(dir.path().join("unchanged.redb")).unwrap();
+
+        let mut sender_mock = MockMtbFileSender::new();
+        sender_mock.expect_send().times(1).returning(|_, _| Err(()));
+        sender_mock
+            .expect_send()
+            .times(1)
+            .returning(|_, options| Ok(SendReport::accepted(&options.request_id.unwrap())));
+
+        let sender = UnchangedSkippingMtbFileSender::new(
+            Arc::new(store),
+            Arc::new(sender_mock) as DynMtbFileSender,
+            "topic",
+        );
+
+        assert!(
+            sender
+                .send(fake_mtb_file("C72.5"), options("request-1"))
+                .await
+                .is_err()
+        );
+        let report = sender
+            .send(fake_mtb_file("C72.5"), options("request-2"))
+            .await
+            .unwrap();
+        assert_eq!(report.request_id, "request-2");
+        assert!(!report.unchanged);
+    }
+
+    #[tokio::test]
+    #[allow(clippy::unwrap_used)]
+    async fn should_send_only_changed_mtb_files_of_batch() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = ContentHashStore::open(dir.path().join("unchanged.redb")).unwrap();
+        let mtb_file = fake_mtb_file("C72.5");
+        store
+            .store(
+                &patient_key("topic", &mtb_file.patient.id),
+                &content_hash(&mtb_file),
+                "request-1",
+            )
+            .unwrap();
+
+        let mut sender_mock = MockMtbFileSender::new();
+        sender_mock
+            .expect_send_batch()
+            .withf(|batch| batch.len() == 1)
+            .times(1)
+            .returning(|_| Ok(vec![SendReport::accepted("request-2")]));
+
+        let sender = UnchangedSkippingMtbFileSender::new(
+            Arc::new(store),
+            Arc::new(sender_mock) as DynMtbFileSender,
+            "topic",
+        );
+
+        let reports = sender
+            .send_batch(vec![
+                (mtb_file, SendOptions::default()),
+                (fake_mtb_file("C71.0"), options("request-2")),
+            ])
+            .await
+            .unwrap();
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[0].request_id, "request-1");
+        assert!(reports[0].unchanged);
+        assert_eq!(reports[1].request_id, "request-2");
+        assert!(!reports[1].unchanged);
+    }
+}
@@ -0,0 +1,410 @@
+use clap::ValueEnum;
+use mv64e_mtb_dto::Mtb;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::LazyLock;
+
+use crate::CONFIG;
+use crate::problem::FieldError;
+
+/// Severities of validation rules as configured
+pub static RULES: LazyLock<RuleSet> =
+    LazyLock::new(|| RuleSet::default().with_severities(&CONFIG.validation_rules));
+
+const VARIANT_LISTS: [&str; 5] = [
+    "simpleVariants",
+    "copyNumberVariants",
+    "dnaFusions",
+    "rnaFusions",
+    "rnaSeqs",
+];
+
+/// Fields containing references, to check references without type
+const REFERENCE_FIELDS: [(&str, Rule); 6] = [
+    ("diagnosis", Rule::Diagnosis),
+    ("diagnoses", Rule::Diagnosis),
+    ("reason", Rule::Diagnosis),
+    ("tumorEntity", Rule::Diagnosis),
+    ("specimen", Rule::Specimen),
+    ("variant", Rule::Variant),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, ValueEnum)]
+pub enum Rule {
+    /// Every patient reference matches the ID of the patient
+    #[serde(rename = "patient-reference")]
+    #[value(name = "patient-reference")]
+    Patient,
+    /// Every referenced diagnosis exists
+    #[serde(rename = "diagnosis-reference")]
+    #[value(name = "diagnosis-reference")]
+    Diagnosis,
+    /// Every referenced tumor specimen exists
+    #[serde(rename = "specimen-reference")]
+    #[value(name = "specimen-reference")]
+    Specimen,
+    /// Every referenced variant exists in an NGS report
+    #[serde(rename = "variant-reference")]
+    #[value(name = "variant-reference")]
+    Variant,
+}
+
+impl Rule {
+    /// Type of references checked by this rule
+    fn reference_type(self) -> &'static str {
+        match self {
+            Rule::Patient => "Patient",
+            Rule::Diagnosis => "MTBDiagnosis",
+            Rule::Specimen => "TumorSpecimen",
+            Rule::Variant => "Variant",
+        }
+    }
+}
+
+impl Display for Rule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = self
+            .to_possible_value()
+            .map(|value| value.get_name().to_string())
+            .unwrap_or_default();
+        f.write_str(&name)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    /// Reject the MTB file
+    Error,
+    /// Forward the MTB file with a warning
+    Warning,
+    /// Do not check this rule
+    Ignore,
+}
+
+/// Severity of a single rule configured by `<rule>=<severity>`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RuleSeverity {
+    pub rule: Rule,
+    pub severity: Severity,
+}
+
+impl FromStr for RuleSeverity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((rule, severity)) = s.split_once('=') else {
+            return Err(format!("Expected '<rule>=<severity>' but got '{s}'"));
+        };
+        Ok(Self {
+            rule: Rule::from_str(rule.trim(), true)?,
+            severity: Severity::from_str(severity.trim(), true)?,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Issue {
+    pub rule: Rule,
+    pub severity: Severity,
+    pub path: String,
+    pub message: String,
+}
+
+impl Display for Issue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[{}] {}: {}", self.rule, self.path, self.message)
+    }
+}
+
+impl From<Issue> for FieldError {
+    fn from(issue: Issue) -> Self {
+        FieldError {
+            path: issue.path,
+            message: format!("[{}] {}", issue.rule, issue.message),
+        }
+    }
+}
+
+pub struct RuleSet(HashMap<Rule, Severity>);
+
+impl Default for RuleSet {
+    fn default() -> Self {
+        Self(HashMap::from([
+            (Rule::Patient, Severity::Error),
+            (Rule::Diagnosis, Severity::Error),
+            (Rule::Specimen, Severity::Error),
+            (Rule::Variant, Severity::Warning),
+        ]))
+    }
+}
+
+impl RuleSet {
+    pub fn with_severities(mut self, severities: &[RuleSeverity]) -> Self {
+        for rule_severity in severities {
+            self.0.insert(rule_severity.rule, rule_severity.severity);
+        }
+        self
+    }
+
+    fn severity(&self, rule: Rule) -> Severity {
+        self.0.get(&rule).copied().unwrap_or(Severity::Error)
+    }
+
+    /// Checks all references within the MTB file and returns all issues found
+    pub fn validate(&self, mtb: &Mtb) -> Vec<Issue> {
+        let Ok(value) = serde_json::to_value(mtb) else {
+            return vec![];
+        };
+
+        let targets = Targets::of(&value);
+        let mut issues = vec![];
+        self.check_references(&value, "", "$", &targets, &mut issues);
+        issues
+    }
+
+    fn check_references(
+        &self,
+        value: &Value,
+        key: &str,
+        path: &str,
+        targets: &Targets,
+        issues: &mut Vec<Issue>,
+    ) {
+        if let Some((rule, id)) = reference(key, value) {
+            let severity = self.severity(rule);
+            if severity != Severity::Ignore && !targets.contains(rule, id) {
+                issues.push(Issue {
+                    rule,
+                    severity,
+                    path: format!("{path}.id"),
+                    message: format!("Referenced {} '{id}' does not exist", rule.reference_type()),
+                });
+            }
+        }
+
+        match value {
+            Value::Object(fields) => {
+                for (key, value) in fields {
+                    self.check_references(value, key, &format!("{path}.{key}"), targets, issues);
+                }
+            }
+            Value::Array(items) => {
+                for (index, item) in items.iter().enumerate() {
+                    let path = format!("{path}[{index}]");
+                    self.check_references(item, key, &path, targets, issues);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Returns the rule and referenced ID if the value, being the field `key` or an item of it, is a reference
+fn reference<'a>(key: &str, value: &'a Value) -> Option<(Rule, &'a str)> {
+    let id = value.get("id")?.as_str()?;
+    if key == "patient" {
+        return Some((Rule::Patient, id));
+    }
+    if let Some(reference_type) = value.get("type").filter(|value| !value.is_null()) {
+        let reference_type = reference_type.as_str()?;
+        return [Rule::Diagnosis, Rule::Specimen, Rule::Variant]
+            .into_iter()
+            .find(|rule| rule.reference_type() == reference_type)
+            .map(|rule| (rule, id));
+    }
+
+    // Without type, only objects containing nothing but the fields of a reference are references
+    let is_reference = value.as_object()?.iter().all(|(field, value)| {
+        matches!(field.as_str(), "id" | "display" | "system") || value.is_null()
+    });
+    REFERENCE_FIELDS
+        .into_iter()
+        .find(|(field, _)| is_reference && *field == key)
+        .map(|(_, rule)| (rule, id))
+}
+
+/// IDs of all entries that can be referenced
+struct Targets<'a> {
+    patient: Option<&'a str>,
+    diagnoses: HashSet<&'a str>,
+    specimens: HashSet<&'a str>,
+    variants: HashSet<&'a str>,
+}
+
+impl<'a> Targets<'a> {
+    fn of(value: &'a Value) -> Self {
+        let ids = |entries: Option<&'a Value>| -> HashSet<&'a str> {
+            entries
+                .and_then(Value::as_array)
+                .into_iter()
+                .flatten()
+                .filter_map(|entry| entry.get("id").and_then(Value::as_str))
+                .collect()
+        };
+
+        let variants = value["ngsReports"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .flat_map(|report| VARIANT_LISTS.map(|list| report["results"].get(list)))
+            .flat_map(ids)
+            .collect();
+
+        Self {
+            patient: value["patient"]["id"].as_str(),
+            diagnoses: ids(value.get("diagnoses")),
+            specimens: ids(value.get("specimens")),
+            variants,
+        }
+    }
+
+    fn contains(&self, rule: Rule, id: &str) -> bool {
+        match rule {
+            Rule::Patient => self.patient == Some(id),
+            Rule::Diagnosis => self.diagnoses.contains(id),
+            Rule::Specimen => self.specimens.contains(id),
+            Rule::Variant => self.variants.contains(id),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::validation::{Issue, Rule, RuleSet, RuleSeverity, Severity};
+    use mv64e_mtb_dto::Mtb;
+    use rstest::rstest;
+    use serde_json::Value;
+    use std::str::FromStr;
+
+    #[allow(clippy::expect_used)]
+    fn mtb_json() -> Value {
+        serde_json::from_str(include_str!("../test-files/mv64e-mtb-fake-patient.json"))
+            .expect("valid JSON")
+    }
+
+    #[allow(clippy::expect_used)]
+    fn mtb(json: Value) -> Mtb {
+        serde_json::from_value(json).expect("valid MTB file")
+    }
+
+    #[test]
+    fn should_not_find_issues_in_consistent_mtb_file() {
+        assert_eq!(RuleSet::default().validate(&mtb(mtb_json())), vec![]);
+    }
+
+    #[test]
+    fn should_find_all_issues() {
+        let mut json = mtb_json();
+        json["episodesOfCare"][0]["patient"]["id"] = "other-patient".into();
+        json["episodesOfCare"][0]["diagnoses"][0]["id"] = "unknown-diagnosis".into();
+        json["ngsReports"][0]["specimen"]["id"] = "unknown-specimen".into();
+        json["carePlans"][0]["medicationRecommendations"][0]["supportingVariants"][0]["variant"]
+            ["id"] = "unknown-variant".into();
+
+        let issues = RuleSet::default().validate(&mtb(json));
+
+        assert_eq!(
+            issues
+                .iter()
+                .map(|issue| (issue.rule, issue.severity, issue.path.as_str()))
+                .collect::<Vec<_>>(),
+            vec![
+                (
+                    Rule::Variant,
+                    Severity::Warning,
+                    "$.carePlans[0].medicationRecommendations[0].supportingVariants[0].variant.id"
+                ),
+                (
+                    Rule::Diagnosis,
+                    Severity::Error,
+                    "$.episodesOfCare[0].diagnoses[0].id"
+                ),
+                (
+                    Rule::Patient,
+                    Severity::Error,
+                    "$.episodesOfCare[0].patient.id"
+                ),
+                (
+                    Rule::Specimen,
+                    Severity::Error,
+                    "$.ngsReports[0].specimen.id"
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    #[allow(clippy::expect_used)]
+    fn should_find_issues_of_references_without_type() {
+        let mut json = mtb_json();
+        let reason = json["carePlans"][0]["reason"]
+            .as_object_mut()
+            .expect("reason reference");
+        reason.remove("type");
+        reason.insert("id".to_string(), "unknown-diagnosis".into());
+        json["ngsReports"][0]["specimen"] = serde_json::json!({ "id": "unknown-specimen" });
+
+        let issues = RuleSet::default().validate(&mtb(json));
+
+        assert_eq!(
+            issues
+                .iter()
+                .map(|issue| (issue.rule, issue.path.as_str()))
+                .collect::<Vec<_>>(),
+            vec![
+                (Rule::Diagnosis, "$.carePlans[0].reason.id"),
+                (Rule::Specimen, "$.ngsReports[0].specimen.id"),
+            ]
+        );
+    }
+
+    #[test]
+    fn should_apply_configured_severities() {
+        let mut json = mtb_json();
+        json["specimens"][0]["diagnosis"]["id"] = "unknown-diagnosis".into();
+        json["ngsReports"][0]["specimen"]["id"] = "unknown-specimen".into();
+
+        let rules = RuleSet::default().with_severities(&[
+            RuleSeverity {
+                rule: Rule::Diagnosis,
+                severity: Severity::Warning,
+            },
+            RuleSeverity {
+                rule: Rule::Specimen,
+                severity: Severity::Ignore,
+            },
+        ]);
+
+        assert_eq!(
+            rules.validate(&mtb(json)),
+            vec![Issue {
+                rule: Rule::Diagnosis,
+                severity: Severity::Warning,
+                path: "$.specimens[0].diagnosis.id".to_string(),
+                message: "Referenced MTBDiagnosis 'unknown-diagnosis' does not exist".to_string(),
+            }]
+        );
+    }
+
+    #[rstest]
+    #[case("patient-reference=error", Ok((Rule::Patient, Severity::Error)))]
+    #[case("variant-reference = ignore", Ok((Rule::Variant, Severity::Ignore)))]
+    #[case("Specimen-Reference=Warning", Ok((Rule::Specimen, Severity::Warning)))]
+    #[case("unknown-reference=error", Err(()))]
+    #[case("patient-reference", Err(()))]
+    fn should_parse_rule_severity(
+        #[case] value: &str,
+        #[case] expected: Result<(Rule, Severity), ()>,
+    ) {
+        assert_eq!(
+            RuleSeverity::from_str(value)
+                .map(|rule_severity| (rule_severity.rule, rule_severity.severity))
+                .map_err(|_| ()),
+            expected
+        );
+    }
+}